@@ -0,0 +1,191 @@
+use std::{collections::BTreeMap, future::Future, pin::Pin, task::Poll};
+
+use futures::{Stream, StreamExt};
+
+use crate::{chat::{ChatCompletion, Content, ChatCompletionDeltaResponse, ChatCompletionSyncResponse, ChatMessage, FinishReason, FunctionCall, Role, ToolCall}, completion::Usage, error::Result, logprobs::ChatLogprobs, util::ExtraFields};
+
+#[derive(Debug, Default)]
+struct ChoiceState {
+    role: Option<Role>,
+    content: Option<String>,
+    refusal: Option<String>,
+    function_call: Option<FunctionCall>,
+    tool_calls: Vec<ToolCall>,
+    finish_reason: Option<FinishReason>,
+    logprobs: Option<ChatLogprobs>,
+    extra: ExtraFields,
+}
+
+impl ChoiceState {
+    fn to_completion(&self, index: i32) -> ChatCompletion {
+        ChatCompletion {
+            index,
+            message: ChatMessage {
+                content: self.content.clone().map(Content::Text),
+                refusal: self.refusal.clone(),
+                function_call: self.function_call.clone(),
+                tool_calls: if self.tool_calls.is_empty() { None } else { Some(self.tool_calls.clone()) },
+                ..ChatMessage::new(self.role.clone().unwrap_or(Role::Assistant), "", None)
+            },
+            finish_reason: self.finish_reason.clone(),
+            logprobs: self.logprobs.clone(),
+            extra: self.extra.clone(),
+        }
+    }
+}
+
+/// Folds streamed chunks into the response a non-streamed request would have returned
+#[derive(Debug, Default)]
+pub struct ChatCompletionAccumulator {
+    id: String,
+    created: u64,
+    model: String,
+    choices: BTreeMap<i32, ChoiceState>,
+    usage: Option<Usage>,
+    system_fingerprint: Option<String>,
+    service_tier: Option<String>,
+    extra: ExtraFields,
+}
+
+impl ChatCompletionAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, chunk: &ChatCompletionDeltaResponse) {
+        self.id.clone_from(&chunk.id);
+        self.created = chunk.created;
+        self.model.clone_from(&chunk.model);
+        if let Some(ref usage) = chunk.usage {
+            self.usage = Some(usage.clone());
+        }
+        if let Some(ref system_fingerprint) = chunk.system_fingerprint {
+            self.system_fingerprint = Some(system_fingerprint.clone());
+        }
+        if let Some(ref service_tier) = chunk.service_tier {
+            self.service_tier = Some(service_tier.clone());
+        }
+        self.extra.extend(chunk.extra.clone());
+
+        for choice in &chunk.choices {
+            let state = self.choices.entry(choice.index).or_default();
+            let delta = &choice.delta;
+
+            if let Some(ref role) = delta.role {
+                state.role = Some(role.clone());
+            }
+            if let Some(ref content) = delta.content {
+                state.content.get_or_insert_with(String::new).push_str(content);
+            }
+            if let Some(ref refusal) = delta.refusal {
+                state.refusal.get_or_insert_with(String::new).push_str(refusal);
+            }
+            if let Some(ref function_call) = delta.function_call {
+                let state = state.function_call.get_or_insert_with(|| FunctionCall { name: String::new(), arguments: String::new() });
+                if let Some(ref name) = function_call.name {
+                    state.name.push_str(name);
+                }
+                if let Some(ref arguments) = function_call.arguments {
+                    state.arguments.push_str(arguments);
+                }
+            }
+            for tool_call in delta.tool_calls.iter().flatten() {
+                tool_call.merge_into(&mut state.tool_calls);
+            }
+            if let Some(ref logprobs) = choice.logprobs {
+                state.logprobs.get_or_insert_with(Default::default).extend(logprobs);
+            }
+            if let Some(ref finish_reason) = choice.finish_reason {
+                state.finish_reason = Some(finish_reason.clone());
+            }
+            state.extra.extend(choice.extra.clone());
+        }
+    }
+
+    /// Usage of the whole request. Only available once the last chunk has been received, and only if it was requested
+    /// through [`StreamOptions`](crate::chat::StreamOptions)
+    pub fn usage(&self) -> Option<&Usage> {
+        self.usage.as_ref()
+    }
+
+    /// Whether every choice seen so far has finished
+    pub fn is_finished(&self) -> bool {
+        !self.choices.is_empty() && self.choices.values().all(|choice| choice.finish_reason.is_some())
+    }
+
+    /// The response so far. Usage is zero unless the server has reported it
+    pub fn response(&self) -> ChatCompletionSyncResponse {
+        ChatCompletionSyncResponse {
+            id: self.id.clone(),
+            object: "chat.completion".to_string(),
+            created: self.created,
+            model: self.model.clone(),
+            choices: self.choices.iter().map(|(index, choice)| choice.to_completion(*index)).collect(),
+            usage: self.usage.clone().unwrap_or_default(),
+            system_fingerprint: self.system_fingerprint.clone(),
+            service_tier: self.service_tier.clone(),
+            extra: self.extra.clone(),
+        }
+    }
+}
+
+/// Passes through streamed chunks while accumulating them into a full response
+#[derive(Debug)]
+pub struct AccumulatingStream<S> {
+    stream: S,
+    accumulator: ChatCompletionAccumulator,
+}
+
+impl<S> AccumulatingStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            accumulator: ChatCompletionAccumulator::new(),
+        }
+    }
+
+    pub fn accumulator(&self) -> &ChatCompletionAccumulator {
+        &self.accumulator
+    }
+
+    /// The response accumulated from all chunks received so far
+    pub fn into_response(self) -> ChatCompletionSyncResponse {
+        self.accumulator.response()
+    }
+}
+
+impl<S> Stream for AccumulatingStream<S>
+where
+    S: Stream<Item = Result<ChatCompletionDeltaResponse>> + Unpin {
+    type Item = Result<ChatCompletionDeltaResponse>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.stream.poll_next_unpin(cx);
+        if let Poll::Ready(Some(Ok(ref chunk))) = poll {
+            self.accumulator.push(chunk);
+        }
+        poll
+    }
+}
+
+pub trait ChatCompletionStreamExt: Stream<Item = Result<ChatCompletionDeltaResponse>> + Sized {
+    /// Accumulate the chunks of this stream into a full response as they pass through
+    fn accumulate(self) -> AccumulatingStream<Self> {
+        AccumulatingStream::new(self)
+    }
+
+    /// Drain the stream, returning only the full response
+    fn collect_response(self) -> impl Future<Output = Result<ChatCompletionSyncResponse>> + Send
+    where
+        Self: Unpin + Send {
+        async move {
+            let mut stream = self.accumulate();
+            while let Some(chunk) = stream.next().await {
+                chunk?;
+            }
+            Ok(stream.into_response())
+        }
+    }
+}
+
+impl<S: Stream<Item = Result<ChatCompletionDeltaResponse>>> ChatCompletionStreamExt for S {}
@@ -0,0 +1,421 @@
+use std::{fmt::Debug, future::Future, path::PathBuf, pin::Pin, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+use reqwest::{header::HeaderMap, Client, StatusCode};
+use serde::Deserialize;
+
+use crate::{error::{Error, Result}, util::parse_duration};
+
+pub type CredentialFuture<'a> = Pin<Box<dyn Future<Output = Result<Credential>> + Send + 'a>>;
+type SecretFuture = Pin<Box<dyn Future<Output = Result<String>> + Send>>;
+type SecretSource = Arc<dyn Fn() -> SecretFuture + Send + Sync>;
+
+const DEFAULT_API_KEY_VAR: &str = "OPENAI_API_KEY";
+const AZURE_AD_SCOPE: &str = "https://cognitiveservices.azure.com/.default";
+/// Azure AD tokens are refreshed this long before they expire
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+/// Longest token lifetime or rate limit reset reported by a server that is taken at its word
+const MAX_REPORTED_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The instant `delay` from now, capped at [`MAX_REPORTED_DELAY`] so that extreme values cannot overflow
+fn instant_after(delay: Duration) -> Instant {
+    let now = Instant::now();
+    now.checked_add(delay.min(MAX_REPORTED_DELAY)).unwrap_or(now)
+}
+
+/// Secret sent with a request
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// API key, sent as a bearer token to OpenAI and in the `api-key` header to Azure
+    ApiKey(String),
+    /// Access token, such as an Azure AD token. Always sent as a bearer token
+    BearerToken(String),
+}
+
+impl Credential {
+    pub fn secret(&self) -> &str {
+        match self {
+            Self::ApiKey(secret) | Self::BearerToken(secret) => secret,
+        }
+    }
+}
+
+// Keep secrets out of logs
+impl Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ApiKey(_) => f.write_str("ApiKey(..)"),
+            Self::BearerToken(_) => f.write_str("BearerToken(..)"),
+        }
+    }
+}
+
+/// Source of the credentials sent with each request
+pub trait AuthProvider: Debug + Send + Sync {
+    /// Credential for the next request. Called before every attempt, so it may change between retries
+    fn credential(&self) -> CredentialFuture<'_>;
+
+    /// Called with the status and headers of every response to a request sent with `credential`
+    fn observe(&self, _credential: &Credential, _status: StatusCode, _headers: &HeaderMap) {}
+}
+
+/// The same credential for every request
+impl AuthProvider for Credential {
+    fn credential(&self) -> CredentialFuture<'_> {
+        Box::pin(std::future::ready(Ok(self.clone())))
+    }
+}
+
+/// API key read from an environment variable before every request
+#[derive(Debug, Clone)]
+pub struct EnvKey {
+    var: String,
+}
+
+impl EnvKey {
+    pub fn new(var: impl Into<String>) -> Self {
+        Self { var: var.into() }
+    }
+}
+
+/// Reads `OPENAI_API_KEY`
+impl Default for EnvKey {
+    fn default() -> Self {
+        Self::new(DEFAULT_API_KEY_VAR)
+    }
+}
+
+impl AuthProvider for EnvKey {
+    fn credential(&self) -> CredentialFuture<'_> {
+        let key = std::env::var(&self.var)
+            .ok()
+            .filter(|key| !key.trim().is_empty())
+            .map(|key| Credential::ApiKey(key.trim().to_string()))
+            .ok_or_else(|| Error::Auth(format!("Environment variable {} is not set", self.var)));
+        Box::pin(std::future::ready(key))
+    }
+}
+
+/// API key read from a file before every request, so that the file can be replaced while the program runs
+#[derive(Debug, Clone)]
+pub struct KeyFile {
+    path: PathBuf,
+}
+
+impl KeyFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl AuthProvider for KeyFile {
+    fn credential(&self) -> CredentialFuture<'_> {
+        Box::pin(async move {
+            let key = tokio::fs::read_to_string(&self.path).await
+                .map_err(|e| Error::Auth(format!("Could not read key file {}: {e}", self.path.display())))?;
+            match key.trim() {
+                "" => Err(Error::Auth(format!("Key file {} is empty", self.path.display()))),
+                key => Ok(Credential::ApiKey(key.to_string())),
+            }
+        })
+    }
+}
+
+/// Credential fetched along with the time it should be fetched again
+#[derive(Debug)]
+struct CachedCredential {
+    credential: Credential,
+    /// `None` if that time is too far in the future to represent
+    refresh_at: Option<Instant>,
+}
+
+impl CachedCredential {
+    fn is_fresh(&self) -> bool {
+        self.refresh_at.is_none_or(|refresh_at| refresh_at > Instant::now())
+    }
+}
+
+/// Cache of a credential that is fetched again once it expires or a request sent with it is rejected
+#[derive(Debug, Default)]
+struct CredentialCache {
+    /// Held while fetching, so that concurrent requests wait for a single refresh
+    cached: tokio::sync::Mutex<Option<CachedCredential>>,
+    /// Credential rejected as unauthorized. Kept apart from `cached`, which may be held by a fetch when the
+    /// rejection is observed
+    rejected: Mutex<Option<Credential>>,
+}
+
+impl CredentialCache {
+    async fn get<F>(&self, fetch: impl FnOnce() -> F) -> Result<Credential>
+    where
+        F: Future<Output = Result<CachedCredential>> {
+        let mut cached = self.cached.lock().await;
+        let rejected = self.rejected.lock().unwrap_or_else(|e| e.into_inner()).take();
+        match *cached {
+            Some(ref cached) if cached.is_fresh() && rejected.as_ref() != Some(&cached.credential) => {
+                Ok(cached.credential.clone())
+            },
+            _ => {
+                *cached = None;
+                let fetched = fetch().await?;
+                let credential = fetched.credential.clone();
+                *cached = Some(fetched);
+                Ok(credential)
+            },
+        }
+    }
+
+    fn reject(&self, credential: &Credential) {
+        *self.rejected.lock().unwrap_or_else(|e| e.into_inner()) = Some(credential.clone());
+    }
+}
+
+/// API key fetched from a secrets source, such as a vault, and cached for `refresh_interval`
+///
+/// The key is also fetched again after a request is rejected as unauthorized, in case it was rotated early.
+pub struct RotatingKey {
+    source: SecretSource,
+    refresh_interval: Duration,
+    cache: CredentialCache,
+}
+
+impl Debug for RotatingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RotatingKey")
+            .field("refresh_interval", &self.refresh_interval)
+            .finish()
+    }
+}
+
+impl RotatingKey {
+    pub fn new<F, Fut>(refresh_interval: Duration, source: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String>> + Send + 'static {
+        Self {
+            source: Arc::new(move || Box::pin(source())),
+            refresh_interval,
+            cache: CredentialCache::default(),
+        }
+    }
+}
+
+impl AuthProvider for RotatingKey {
+    fn credential(&self) -> CredentialFuture<'_> {
+        Box::pin(self.cache.get(|| async {
+            Ok(CachedCredential {
+                credential: Credential::ApiKey((self.source)().await?),
+                refresh_at: Instant::now().checked_add(self.refresh_interval),
+            })
+        }))
+    }
+
+    fn observe(&self, credential: &Credential, status: StatusCode, _headers: &HeaderMap) {
+        if status == StatusCode::UNAUTHORIZED {
+            self.cache.reject(credential);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// Azure AD access token for a service principal, obtained through the client credentials flow and refreshed
+/// shortly before it expires
+pub struct AzureAdToken {
+    client: Client,
+    token_url: String,
+    client_id: String,
+    client_secret: String,
+    scope: String,
+    cache: CredentialCache,
+}
+
+impl Debug for AzureAdToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AzureAdToken")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .field("scope", &self.scope)
+            .finish()
+    }
+}
+
+impl AzureAdToken {
+    pub fn new(tenant_id: impl AsRef<str>, client_id: impl Into<String>, client_secret: impl Into<String>) -> Self {
+        Self {
+            client: Client::new(),
+            token_url: format!("https://login.microsoftonline.com/{}/oauth2/v2.0/token", tenant_id.as_ref()),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scope: AZURE_AD_SCOPE.to_string(),
+            cache: CredentialCache::default(),
+        }
+    }
+
+    /// Request tokens through `client`, e.g. the [`Context`](crate::context::Context)'s own client so that they go
+    /// through the same proxy and use the same timeouts and TLS settings
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Request tokens from another endpoint, e.g. that of a national cloud
+    pub fn with_token_url(mut self, token_url: impl Into<String>) -> Self {
+        self.token_url = token_url.into();
+        self
+    }
+
+    pub fn with_scope(mut self, scope: impl Into<String>) -> Self {
+        self.scope = scope.into();
+        self
+    }
+
+    async fn fetch(&self) -> Result<CachedCredential> {
+        let response = self.client.post(&self.token_url)
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("scope", &self.scope),
+            ])
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(Error::from_response(status, response.text().await?));
+        }
+
+        let token = response.json::<TokenResponse>().await?;
+        Ok(CachedCredential {
+            credential: Credential::BearerToken(token.access_token),
+            refresh_at: Some(instant_after(Duration::from_secs(token.expires_in).saturating_sub(TOKEN_REFRESH_MARGIN))),
+        })
+    }
+}
+
+impl AuthProvider for AzureAdToken {
+    fn credential(&self) -> CredentialFuture<'_> {
+        Box::pin(self.cache.get(|| self.fetch()))
+    }
+
+    fn observe(&self, credential: &Credential, status: StatusCode, _headers: &HeaderMap) {
+        if status == StatusCode::UNAUTHORIZED {
+            self.cache.reject(credential);
+        }
+    }
+}
+
+/// How a [`KeyPool`] picks the key for each request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeySelection {
+    /// Use each key in turn
+    #[default]
+    RoundRobin,
+    /// Use the key with the most requests left according to the `x-ratelimit-*` headers. Keys that have not been
+    /// used yet, or whose limit has been reset since, are preferred
+    MostRemaining,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct KeyQuota {
+    remaining_requests: Option<u32>,
+    reset_at: Option<Instant>,
+}
+
+impl KeyQuota {
+    fn remaining(&self, now: Instant) -> u32 {
+        match (self.remaining_requests, self.reset_at) {
+            (Some(_), Some(reset_at)) if reset_at <= now => u32::MAX,
+            (Some(remaining), _) => remaining,
+            (None, _) => u32::MAX,
+        }
+    }
+}
+
+/// Several API keys, e.g. of different projects, used to spread requests over their rate limits
+pub struct KeyPool {
+    keys: Vec<String>,
+    selection: KeySelection,
+    next: AtomicUsize,
+    quotas: Mutex<Vec<KeyQuota>>,
+}
+
+impl Debug for KeyPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyPool")
+            .field("keys", &self.keys.len())
+            .field("selection", &self.selection)
+            .finish()
+    }
+}
+
+impl KeyPool {
+    pub fn new(keys: impl IntoIterator<Item = impl Into<String>>, selection: KeySelection) -> Self {
+        let keys = keys.into_iter().map(Into::into).collect::<Vec<String>>();
+        Self {
+            quotas: Mutex::new(vec![KeyQuota::default(); keys.len()]),
+            keys,
+            selection,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn select(&self) -> Option<&str> {
+        if self.keys.is_empty() {
+            return None;
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let index = match self.selection {
+            KeySelection::RoundRobin => start % self.keys.len(),
+            KeySelection::MostRemaining => {
+                let quotas = self.quotas.lock().unwrap();
+                let now = Instant::now();
+                // Ties go to the next key in turn
+                (0..self.keys.len())
+                    .map(|offset| (start + offset) % self.keys.len())
+                    .min_by_key(|index| std::cmp::Reverse(quotas[*index].remaining(now)))
+                    .expect("The pool is not empty")
+            },
+        };
+        Some(&self.keys[index])
+    }
+}
+
+impl AuthProvider for KeyPool {
+    fn credential(&self) -> CredentialFuture<'_> {
+        let key = self.select()
+            .map(|key| Credential::ApiKey(key.to_string()))
+            .ok_or_else(|| Error::Auth("The key pool is empty".to_string()));
+        Box::pin(std::future::ready(key))
+    }
+
+    fn observe(&self, credential: &Credential, status: StatusCode, headers: &HeaderMap) {
+        let Some(index) = self.keys.iter().position(|key| key == credential.secret()) else {
+            return;
+        };
+
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let remaining = header("x-ratelimit-remaining-requests").and_then(|value| value.parse::<u32>().ok());
+        let reset = header("x-ratelimit-reset-requests").and_then(parse_duration);
+
+        let mut quotas = self.quotas.lock().unwrap();
+        let quota = &mut quotas[index];
+        match remaining {
+            Some(remaining) => quota.remaining_requests = Some(remaining),
+            // Rate limited without saying for how long. Limits are per minute
+            None if status == StatusCode::TOO_MANY_REQUESTS => {
+                quota.remaining_requests = Some(0);
+                quota.reset_at = Some(Instant::now() + Duration::from_secs(60));
+            },
+            None => {},
+        }
+        if let Some(reset) = reset {
+            quota.reset_at = Some(instant_after(reset));
+        }
+    }
+}
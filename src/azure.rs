@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use derive_builder::Builder;
+use serde::Deserialize;
+
+pub(crate) const DEFAULT_API_VERSION: &str = "2024-10-21";
+
+/// Endpoints that Azure serves per deployment, under `/openai/deployments/{deployment}/`
+const DEPLOYMENT_ENDPOINTS: &[&str] = &["chat/", "completions", "embeddings", "images/", "audio/"];
+
+/// Settings for talking to Azure OpenAI instead of OpenAI
+///
+/// Azure serves each model through a named deployment. The `model` of a request is looked up in `deployments`, and is
+/// used as the deployment name itself if it is not found there.
+#[derive(Debug, Clone, Builder, Deserialize)]
+#[builder(pattern = "owned")]
+#[serde(default, deny_unknown_fields)]
+pub struct AzureConfig {
+    /// Sent as the `api-version` query parameter of every request
+    #[builder(setter(into), default = "DEFAULT_API_VERSION.to_string()")]
+    pub api_version: String,
+    /// Deployment names by model name
+    #[builder(setter(custom), default)]
+    pub deployments: HashMap<String, String>,
+    /// Deployment for requests that do not name a model
+    #[builder(setter(into, strip_option), default)]
+    pub default_deployment: Option<String>,
+}
+
+impl AzureConfigBuilder {
+    /// Serve requests for `model` with `deployment`
+    pub fn deployment(mut self, model: impl Into<String>, deployment: impl Into<String>) -> Self {
+        self.deployments.get_or_insert_with(HashMap::new).insert(model.into(), deployment.into());
+        self
+    }
+}
+
+impl Default for AzureConfig {
+    fn default() -> Self {
+        AzureConfigBuilder::default().build().expect("All AzureConfig fields have defaults")
+    }
+}
+
+impl AzureConfig {
+    /// Deployment serving `model`
+    pub fn deployment<'a>(&'a self, model: Option<&'a str>) -> Option<&'a str> {
+        match model {
+            Some(model) => Some(self.deployments.get(model).map(String::as_str).unwrap_or(model)),
+            None => self.default_deployment.as_deref(),
+        }
+    }
+
+    /// Path of an endpoint relative to the base URL, e.g. `openai/deployments/gpt-4o/chat/completions`
+    pub(crate) fn path(&self, path: &str, model: Option<&str>) -> String {
+        let path = path.trim_start_matches('/');
+        match self.deployment(model) {
+            Some(deployment) if DEPLOYMENT_ENDPOINTS.iter().any(|endpoint| path.starts_with(endpoint)) => format!("openai/deployments/{deployment}/{path}"),
+            _ => format!("openai/{path}"),
+        }
+    }
+}
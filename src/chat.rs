@@ -1,68 +1,523 @@
-use std::{collections::HashMap, str::FromStr, pin::Pin, task::Poll};
+use std::{collections::HashMap, str::FromStr};
 
+use base64::{prelude::BASE64_STANDARD, Engine};
 use derive_builder::Builder;
-use futures::{Stream, StreamExt};
-use reqwest::{Client, RequestBuilder};
-use reqwest_eventsource::{RequestBuilderExt, Event, EventSource};
-use serde::{Serialize, Deserialize};
+use futures::Stream;
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize, de::DeserializeOwned, ser::SerializeMap};
 
-use crate::{completion::{Sequence, Usage}, context::{API_URL, Context}};
+use crate::{completion::{Sequence, Usage}, context::Context, error::{Error, Result}, logprobs::ChatLogprobs, metadata::WithMetadata, request::ApiRequest, stream::CompletionStream, util::{data_url, json_schema, strict_json_schema, ExtraFields, FileResource}};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Role {
     User,
     System,
-    Assistant
+    /// Instructions that take precedence over user messages, replacing `system` for reasoning models
+    Developer,
+    Assistant,
+    Function,
+    Tool,
+    /// A role this version of the crate does not know about
+    Unknown(String),
 }
 
 impl Serialize for Role {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl Role {
+    pub fn as_str(&self) -> &str {
         match self {
-            Self::User => serializer.serialize_str("user"),
-            Self::System => serializer.serialize_str("system"),
-            Self::Assistant => serializer.serialize_str("assistant"),
+            Self::User => "user",
+            Self::System => "system",
+            Self::Developer => "developer",
+            Self::Assistant => "assistant",
+            Self::Function => "function",
+            Self::Tool => "tool",
+            Self::Unknown(role) => role,
         }
     }
 }
 
 impl<'de> Deserialize<'de> for Role {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
         D: serde::Deserializer<'de> {
             // Deserialize the String
             match String::deserialize(deserializer)? {
                 s if s == "user" => Ok(Self::User),
                 s if s == "system" => Ok(Self::System),
+                s if s == "developer" => Ok(Self::Developer),
                 s if s == "assistant" => Ok(Self::Assistant),
-                _ => Err(serde::de::Error::custom("Invalid role")),
+                s if s == "function" => Ok(Self::Function),
+                s if s == "tool" => Ok(Self::Tool),
+                s => Ok(Self::Unknown(s)),
             }
 
     }
 }
 
+/// A function the model has asked to be called
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON-encoded arguments. The model may produce invalid JSON or arguments not matching the schema
+    pub arguments: String,
+}
+
+impl FunctionCall {
+    pub fn parse_arguments<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_str(&self.arguments)
+    }
+}
+
+/// A function the model may call
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// JSON Schema object describing the arguments
+    pub parameters: serde_json::Value,
+}
+
+impl FunctionDefinition {
+    pub fn new(name: impl Into<String>, description: Option<String>, parameters: serde_json::Value) -> Self {
+        Self {
+            name: name.into(),
+            description,
+            parameters,
+        }
+    }
+}
+
+/// Whether the model should call a function
+#[derive(Debug, Clone)]
+pub enum FunctionCallMode {
+    /// Respond with a message instead of calling a function
+    None,
+    /// Let the model decide
+    Auto,
+    /// Call the named function
+    Force(String),
+}
+
+impl Serialize for FunctionCallMode {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        match self {
+            Self::None => serializer.serialize_str("none"),
+            Self::Auto => serializer.serialize_str("auto"),
+            Self::Force(name) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("name", name)?;
+                map.end()
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FunctionCallMode {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de> {
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::String(s) if s == "none" => Ok(Self::None),
+            serde_json::Value::String(s) if s == "auto" => Ok(Self::Auto),
+            serde_json::Value::Object(map) => match map.get("name").and_then(|name| name.as_str()) {
+                Some(name) => Ok(Self::Force(name.to_string())),
+                None => Err(serde::de::Error::custom("Invalid function call mode")),
+            },
+            _ => Err(serde::de::Error::custom("Invalid function call mode")),
+        }
+    }
+}
+
+/// A tool the model may call
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Tool {
+    Function {
+        function: FunctionDefinition,
+    },
+}
+
+impl Tool {
+    pub fn function(function: FunctionDefinition) -> Self {
+        Self::Function { function }
+    }
+}
+
+/// Which tool, if any, the model should call
+#[derive(Debug, Clone)]
+pub enum ToolChoice {
+    /// Respond with a message instead of calling a tool
+    None,
+    /// Let the model decide
+    Auto,
+    /// Call at least one tool
+    Required,
+    /// Call the named function
+    Function(String),
+}
+
+impl Serialize for ToolChoice {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        match self {
+            Self::None => serializer.serialize_str("none"),
+            Self::Auto => serializer.serialize_str("auto"),
+            Self::Required => serializer.serialize_str("required"),
+            Self::Function(name) => {
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry("type", "function")?;
+                map.serialize_entry("function", &serde_json::json!({ "name": name }))?;
+                map.end()
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ToolChoice {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de> {
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::String(s) if s == "none" => Ok(Self::None),
+            serde_json::Value::String(s) if s == "auto" => Ok(Self::Auto),
+            serde_json::Value::String(s) if s == "required" => Ok(Self::Required),
+            serde_json::Value::Object(map) => match map.get("function").and_then(|function| function.get("name")).and_then(|name| name.as_str()) {
+                Some(name) => Ok(Self::Function(name.to_string())),
+                None => Err(serde::de::Error::custom("Invalid tool choice")),
+            },
+            _ => Err(serde::de::Error::custom("Invalid tool choice")),
+        }
+    }
+}
+
+/// Schema the model's response must match
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonSchemaFormat {
+    /// Identifies the schema. May only contain letters, digits, underscores and dashes
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub schema: serde_json::Value,
+    /// Guarantee that the response matches the schema. Only a subset of JSON Schema is supported in strict mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+/// Format of the model's response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    /// Any valid JSON. The messages must also ask for JSON
+    JsonObject,
+    JsonSchema {
+        json_schema: JsonSchemaFormat,
+    },
+}
+
+impl ResponseFormat {
+    /// Respond with JSON matching the schema of `T`
+    pub fn json_schema<T: JsonSchema>(strict: bool) -> Self {
+        let name = T::schema_name().chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .take(64)
+            .collect();
+
+        Self::JsonSchema {
+            json_schema: JsonSchemaFormat {
+                name,
+                description: None,
+                schema: if strict { strict_json_schema::<T>() } else { json_schema::<T>() },
+                strict: Some(strict),
+            },
+        }
+    }
+}
+
+/// A tool call made by the model. Several may be requested at once
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ToolCall {
+    /// Identifies the call when sending back its result
+    pub id: String,
+    /// Always `function` at present
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: FunctionCall,
+}
+
+/// How closely the model should look at an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageDetail {
+    Auto,
+    Low,
+    High,
+    Unknown(String),
+}
+
+impl ImageDetail {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Auto => "auto",
+            Self::Low => "low",
+            Self::High => "high",
+            Self::Unknown(detail) => detail,
+        }
+    }
+}
+
+impl Serialize for ImageDetail {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageDetail {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de> {
+        match String::deserialize(deserializer)? {
+            s if s == "auto" => Ok(Self::Auto),
+            s if s == "low" => Ok(Self::Low),
+            s if s == "high" => Ok(Self::High),
+            s => Ok(Self::Unknown(s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageUrl {
+    /// Web URL or `data:` URL of the image
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ImageDetail>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputAudioFormat {
+    Wav,
+    Mp3,
+    Unknown(String),
+}
+
+impl InputAudioFormat {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Wav => "wav",
+            Self::Mp3 => "mp3",
+            Self::Unknown(format) => format,
+        }
+    }
+}
+
+impl Serialize for InputAudioFormat {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for InputAudioFormat {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de> {
+        match String::deserialize(deserializer)? {
+            s if s == "wav" => Ok(Self::Wav),
+            s if s == "mp3" => Ok(Self::Mp3),
+            s => Ok(Self::Unknown(s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputAudio {
+    /// Base64-encoded audio
+    pub data: String,
+    pub format: InputAudioFormat,
+}
+
+/// Part of a message mixing text with images or audio
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text {
+        text: String,
+    },
+    ImageUrl {
+        image_url: ImageUrl,
+    },
+    InputAudio {
+        input_audio: InputAudio,
+    },
+}
+
+impl ContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Text { text: text.into() }
+    }
+
+    pub fn image_url(url: impl Into<String>, detail: Option<ImageDetail>) -> Self {
+        Self::ImageUrl { image_url: ImageUrl { url: url.into(), detail } }
+    }
+
+    /// Image sent inline, e.g. `image/png` data
+    pub fn image_data(mime_type: &str, data: &[u8], detail: Option<ImageDetail>) -> Self {
+        Self::image_url(data_url(mime_type, data), detail)
+    }
+
+    /// Image read from a file or buffer and sent inline
+    pub async fn image_file(mime_type: &str, file: impl Into<FileResource>, detail: Option<ImageDetail>) -> Result<Self> {
+        Ok(Self::image_data(mime_type, &file.into().read_to_vec().await?, detail))
+    }
+
+    pub fn input_audio(data: &[u8], format: InputAudioFormat) -> Self {
+        Self::InputAudio { input_audio: InputAudio { data: BASE64_STANDARD.encode(data), format } }
+    }
+}
+
+/// Content of a message: plain text, or parts mixing text with images or audio
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl Content {
+    /// The content if it is plain text
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Self::Text(text) => Some(text),
+            Self::Parts(_) => None,
+        }
+    }
+
+    /// All text of the content, leaving out other parts
+    pub fn to_text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            Self::Parts(parts) => parts.iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl From<Vec<ContentPart>> for Content {
+    fn from(parts: Vec<ContentPart>) -> Self {
+        Self::Parts(parts)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatMessage {
     pub role: Role,
-    pub content: String,
-    
+    /// Absent when the assistant calls a function
+    #[serde(default)]
+    pub content: Option<Content>,
+    /// Set instead of `content` when the model declines to produce structured output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
+
+    /// Name of the author, or of the function for [`Role::Function`] messages
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<FunctionCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// The call a [`Role::Tool`] message is the result of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
 impl ChatMessage {
     pub fn new(role: Role, message: impl Into<String>, name: Option<String>) -> Self {
         Self {
             role,
-            content: message.into(),
-            name
+            content: Some(Content::Text(message.into())),
+            refusal: None,
+            name,
+            function_call: None,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// Message mixing text with images or audio
+    pub fn with_parts(role: Role, parts: Vec<ContentPart>, name: Option<String>) -> Self {
+        Self {
+            content: Some(Content::Parts(parts)),
+            ..Self::new(role, "", name)
+        }
+    }
+
+    /// The content if it is plain text
+    pub fn text(&self) -> Option<&str> {
+        self.content.as_ref().and_then(Content::as_text)
+    }
+
+    /// Deserialize the JSON content of a reply to a request with a JSON [`ResponseFormat`]
+    pub fn parse_content<T: DeserializeOwned>(&self) -> Result<T> {
+        if let Some(ref refusal) = self.refusal {
+            return Err(Error::Refusal(refusal.clone()));
+        }
+
+        let content = self.content.as_ref().map(Content::to_text).unwrap_or_default();
+        serde_json::from_str(&content).map_err(|error| Error::InvalidOutput { content, error })
+    }
+
+    /// Result of calling a function, to be sent back to the model
+    pub fn function_result(name: impl Into<String>, result: impl Into<String>) -> Self {
+        Self::new(Role::Function, result, Some(name.into()))
+    }
+
+    /// Result of a tool call, to be sent back to the model
+    pub fn tool_result(tool_call_id: impl Into<String>, result: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.into()),
+            ..Self::new(Role::Tool, result, None)
         }
     }
 }
 
-#[derive(Debug, Serialize, Builder)]
-#[builder(pattern = "owned")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamOptions {
+    /// Send a final chunk with the token usage of the whole request
+    pub include_usage: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Builder)]
+#[builder(pattern = "owned", derive(Clone, Debug))]
 pub struct ChatHistory {
     #[builder(setter(into))]
     pub messages: Vec<ChatMessage>,
@@ -78,8 +533,13 @@ pub struct ChatHistory {
     #[builder(setter(into, strip_option), default)]
     pub n: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    /// Set by [`Context::create_chat_completion_sync`] and [`Context::create_chat_completion_streamed`]
+    #[builder(setter(into, strip_option), default)]
+    pub stream: Option<bool>,
+    /// Only valid for streamed requests
+    #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(setter(into, strip_option), default)]
-    stream: Option<bool>,
+    pub stream_options: Option<StreamOptions>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(setter(into, strip_option), default)]
     pub stop: Option<Sequence>,
@@ -98,17 +558,104 @@ pub struct ChatHistory {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(setter(into, strip_option), default)]
     pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    pub functions: Option<Vec<FunctionDefinition>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    pub function_call: Option<FunctionCallMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    pub tool_choice: Option<ToolChoice>,
+    /// Allow the model to request several tool calls in one message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    pub parallel_tool_calls: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    pub response_format: Option<ResponseFormat>,
+    /// Return the log probability of each token of the reply
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    pub logprobs: Option<bool>,
+    /// Also return the most likely alternatives to each token, up to 20. Requires `logprobs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
+    pub top_logprobs: Option<u32>,
+}
+
+impl From<ChatHistory> for ChatHistoryBuilder {
+    /// Builder for a request equal to `history`, to continue it
+    fn from(history: ChatHistory) -> Self {
+        Self {
+            messages: Some(history.messages),
+            model: Some(history.model),
+            temperature: Some(history.temperature),
+            top_p: Some(history.top_p),
+            n: Some(history.n),
+            stream: Some(history.stream),
+            stream_options: Some(history.stream_options),
+            stop: Some(history.stop),
+            max_tokens: Some(history.max_tokens),
+            presence_penalty: Some(history.presence_penalty),
+            frequency_penalty: Some(history.frequency_penalty),
+            logit_bias: Some(history.logit_bias),
+            user: Some(history.user),
+            functions: Some(history.functions),
+            function_call: Some(history.function_call),
+            tools: Some(history.tools),
+            tool_choice: Some(history.tool_choice),
+            parallel_tool_calls: Some(history.parallel_tool_calls),
+            response_format: Some(history.response_format),
+            logprobs: Some(history.logprobs),
+            top_logprobs: Some(history.top_logprobs),
+        }
+    }
 }
 
-#[derive(Debug)]
+impl From<ChatHistoryBuilderError> for Error {
+    fn from(e: ChatHistoryBuilderError) -> Self {
+        Error::Builder(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FinishReason {
     Stop,
     Length,
     ContentFilter,
+    FunctionCall,
+    ToolCalls,
+    /// A reason this version of the crate does not know about
+    Unknown(String),
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Stop => "stop",
+            Self::Length => "length",
+            Self::ContentFilter => "content_filter",
+            Self::FunctionCall => "function_call",
+            Self::ToolCalls => "tool_calls",
+            Self::Unknown(reason) => reason,
+        }
+    }
+}
+
+impl Serialize for FinishReason {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        serializer.serialize_str(self.as_str())
+    }
 }
 
 impl<'de> Deserialize<'de> for FinishReason {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
         D: serde::Deserializer<'de> {
             // Deserialize the String
@@ -116,110 +663,176 @@ impl<'de> Deserialize<'de> for FinishReason {
                 s if s == "stop" => Ok(Self::Stop),
                 s if s == "length" => Ok(Self::Length),
                 s if s == "content_filter" => Ok(Self::ContentFilter),
-                _ => Err(serde::de::Error::custom("Invalid stop reason")),
+                s if s == "function_call" => Ok(Self::FunctionCall),
+                s if s == "tool_calls" => Ok(Self::ToolCalls),
+                s => Ok(Self::Unknown(s)),
             }
 
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatCompletion {
     pub index: i32,
     pub message: ChatMessage,
-    pub finish_reason: Option<FinishReason>
+    pub finish_reason: Option<FinishReason>,
+    #[serde(default)]
+    pub logprobs: Option<ChatLogprobs>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
-#[derive(Debug, Deserialize)]
+/// Fragment of a function call. The name arrives in the first fragment, the arguments are split across all of them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeltaFunctionCall {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+/// Fragment of one of possibly several concurrent tool calls, identified by `index`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeltaToolCall {
+    pub index: usize,
+    /// Only present in the first fragment of each call
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub tool_type: Option<String>,
+    pub function: Option<DeltaFunctionCall>,
+}
+
+impl DeltaToolCall {
+    /// Fold this fragment into the tool calls reassembled so far
+    pub fn merge_into(&self, calls: &mut Vec<ToolCall>) {
+        while calls.len() <= self.index {
+            calls.push(ToolCall {
+                id: String::new(),
+                tool_type: "function".to_string(),
+                function: FunctionCall { name: String::new(), arguments: String::new() },
+            });
+        }
+
+        let call = &mut calls[self.index];
+        if let Some(ref id) = self.id {
+            call.id.push_str(id);
+        }
+        if let Some(ref tool_type) = self.tool_type {
+            call.tool_type.clone_from(tool_type);
+        }
+        if let Some(ref function) = self.function {
+            if let Some(ref name) = function.name {
+                call.function.name.push_str(name);
+            }
+            if let Some(ref arguments) = function.arguments {
+                call.function.arguments.push_str(arguments);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct DeltaMessage {
     pub role: Option<Role>,
     pub content: Option<String>,
+    #[serde(default)]
+    pub refusal: Option<String>,
+    pub function_call: Option<DeltaFunctionCall>,
+    pub tool_calls: Option<Vec<DeltaToolCall>>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct DeltaChatCompletion {
     pub index: i32,
     pub delta: DeltaMessage,
     pub finish_reason: Option<FinishReason>,
+    #[serde(default)]
+    pub logprobs: Option<ChatLogprobs>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
-#[derive(Debug, Deserialize)]
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct ChatCompletionDeltaResponse {
     pub id: String,
-    /* pub object: "chat.completion", */
+    #[serde(default)]
+    pub object: String,
     pub created: u64,
     pub model: String,
     pub choices: Vec<DeltaChatCompletion>,
+    /// Only sent in the last chunk, with no choices, if requested through [`StreamOptions`]
+    pub usage: Option<Usage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl FromStr for ChatCompletionDeltaResponse {
     type Err = serde_json::Error;
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         serde_json::from_str(s)
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatCompletionSyncResponse {
     pub id: String,
-    /* pub object: "chat.completion", */
+    #[serde(default)]
+    pub object: String,
     pub created: u64,
     pub model: String,
     pub choices: Vec<ChatCompletion>,
-    pub usage: Usage
-}
-
-struct CompletionStream {
-    stream: EventSource
-}
-
-impl Stream for CompletionStream {
-    type Item = anyhow::Result<ChatCompletionDeltaResponse>;
-
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
-        loop {
-            return match self.stream.poll_next_unpin(cx) {
-                Poll::Ready(Some(Ok(event))) => {
-                    match event {
-                        Event::Message(message) => {
-                            // Stream has ended
-                            if message.data == "[DONE]" {
-                                return Poll::Ready(None)
-                            }
-
-                            match message.data.parse::<ChatCompletionDeltaResponse>() {
-                                Ok(value) => Poll::Ready(Some(Ok(value))),
-                                Err(e) => Poll::Ready(Some(Err(e.into())))
-                            }
-                        },
-                        _ => continue
-                    }
-                },
-                Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(anyhow::Error::new(e)))),
-                Poll::Ready(None) => Poll::Ready(None),
-                Poll::Pending => Poll::Pending
-            }
-        }
-    }
+    pub usage: Usage,
+    /// Identifies the backend configuration that served the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+    /// Processing tier the request was served with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_tier: Option<String>,
+    /// Fields not known to this crate, kept so that nothing the server sent is lost
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Context {
-    fn build_request(&self, stream: bool, chat_completion_request: ChatHistoryBuilder) -> anyhow::Result<RequestBuilder> {
-        Ok(self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/chat/completions")))
-            .json(&chat_completion_request.stream(stream).build()?))
+    fn build_request(&self, stream: bool, chat_completion_request: ChatHistoryBuilder) -> Result<ApiRequest> {
+        ApiRequest::post("chat/completions").json(&chat_completion_request.stream(stream).build()?)
+    }
+
+    pub async fn create_chat_completion_sync(&self, chat_completion_request: ChatHistoryBuilder) -> Result<ChatCompletionSyncResponse> {
+        Ok(self.create_chat_completion_sync_with_metadata(chat_completion_request).await?.data)
     }
 
-    pub async fn create_chat_completion_sync(&self, chat_completion_request: ChatHistoryBuilder) -> anyhow::Result<ChatCompletionSyncResponse> {
-        Ok(
-            self.build_request(false, chat_completion_request)?
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<ChatCompletionSyncResponse>()
-                .await?
-        )
+    /// Like [`create_chat_completion_sync`](Context::create_chat_completion_sync), along with the metadata of the response
+    pub async fn create_chat_completion_sync_with_metadata(&self, chat_completion_request: ChatHistoryBuilder) -> Result<WithMetadata<ChatCompletionSyncResponse>> {
+        self.execute_json_with_metadata::<ChatCompletionSyncResponse>(self.build_request(false, chat_completion_request)?).await
     }
 
-    pub async fn create_chat_completion_streamed(&self, chat_completion_request: ChatHistoryBuilder) -> anyhow::Result<impl Stream<Item = anyhow::Result<ChatCompletionDeltaResponse>>> {
-        Ok(CompletionStream { stream: self.build_request(true, chat_completion_request)?.eventsource()? })
+    /// Request a response matching the JSON Schema of `T` in strict mode, and deserialize it
+    pub async fn create_chat_completion_typed<T: DeserializeOwned + JsonSchema>(&self, chat_completion_request: ChatHistoryBuilder) -> Result<T> {
+        Ok(self.create_chat_completion_typed_with_metadata(chat_completion_request).await?.data)
     }
-}
\ No newline at end of file
+
+    /// Like [`create_chat_completion_typed`](Context::create_chat_completion_typed), along with the metadata of the response
+    pub async fn create_chat_completion_typed_with_metadata<T: DeserializeOwned + JsonSchema>(&self, chat_completion_request: ChatHistoryBuilder) -> Result<WithMetadata<T>> {
+        let response = self.create_chat_completion_sync_with_metadata(chat_completion_request.response_format(ResponseFormat::json_schema::<T>(true))).await?;
+        let data = match response.choices.first() {
+            Some(choice) => choice.message.parse_content()?,
+            None => return Err(Error::InvalidOutput { content: String::new(), error: serde::de::Error::custom("Response has no choices") }),
+        };
+        Ok(WithMetadata { data, metadata: response.metadata })
+    }
+
+    pub async fn create_chat_completion_streamed(&self, chat_completion_request: ChatHistoryBuilder) -> Result<impl Stream<Item = Result<ChatCompletionDeltaResponse>> + Send + Unpin> {
+        Ok(self.create_chat_completion_streamed_with_metadata(chat_completion_request).await?.data)
+    }
+
+    /// Like [`create_chat_completion_streamed`](Context::create_chat_completion_streamed), along with the metadata of
+    /// the response, which is available before the first chunk
+    pub async fn create_chat_completion_streamed_with_metadata(&self, chat_completion_request: ChatHistoryBuilder) -> Result<WithMetadata<impl Stream<Item = Result<ChatCompletionDeltaResponse>> + Send + Unpin>> {
+        let (response, metadata) = self.execute_with_metadata(self.build_request(true, chat_completion_request)?).await?;
+        Ok(WithMetadata { data: CompletionStream::<ChatCompletionDeltaResponse>::new(response), metadata })
+    }
+}
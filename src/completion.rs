@@ -1,19 +1,20 @@
 use std::collections::HashMap;
 
 use derive_builder::Builder;
-use reqwest::Client;
+use futures::Stream;
 use serde::{Serialize, Deserialize};
 
-use crate::context::{API_URL, Context};
+use crate::{context::Context, error::Result, logprobs::CompletionLogprobs, metadata::WithMetadata, request::ApiRequest, stream::CompletionStream, util::ExtraFields};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
 pub enum Sequence {
     String(String),
     List(Vec<String>),
 }
 
 impl Serialize for Sequence {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
@@ -111,15 +112,54 @@ pub struct CompletionRequest {
 pub struct Choice {
     pub index: u64,
     pub text: String,
-    pub logprobs: Option<HashMap<String, f64>>,
+    pub logprobs: Option<CompletionLogprobs>,
     pub finish_reason: String,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
-#[derive(Debug, Deserialize)]
+/// Breakdown of the prompt tokens
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PromptTokensDetails {
+    /// Tokens read from the prompt cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cached_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_tokens: Option<u64>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
+}
+
+/// Breakdown of the completion tokens
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CompletionTokensDetails {
+    /// Tokens the model used for reasoning, which are billed but not part of the output
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_tokens: Option<u64>,
+    /// Tokens of a predicted output that appeared in the completion
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accepted_prediction_tokens: Option<u64>,
+    /// Tokens of a predicted output that did not appear in the completion. They are billed like completion tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rejected_prediction_tokens: Option<u64>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Usage {
     pub prompt_tokens: u64,
+    #[serde(default)]
     pub completion_tokens: u64,
     pub total_tokens: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Deserialize)]
@@ -130,18 +170,59 @@ pub struct CompletionResponse {
     pub model: String,
     pub choices: Vec<Choice>,
     pub usage: Usage,
+    /// Identifies the backend configuration that served the request
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeltaCompletion {
+    pub index: u64,
+    pub text: String,
+    pub logprobs: Option<CompletionLogprobs>,
+    /// Only set on the last chunk of each choice
+    pub finish_reason: Option<String>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompletionDeltaResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<DeltaCompletion>,
+    pub usage: Option<Usage>,
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Context {
-    pub async fn create_completion(&self, completion_request: CompletionRequest) -> anyhow::Result<CompletionResponse> {
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/completions")))
-                .json(&completion_request)
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<CompletionResponse>()
-                .await?
-        )
+    pub async fn create_completion(&self, completion_request: CompletionRequest) -> Result<CompletionResponse> {
+        Ok(self.create_completion_with_metadata(completion_request).await?.data)
+    }
+
+    /// Like [`create_completion`](Context::create_completion), along with the metadata of the response
+    pub async fn create_completion_with_metadata(&self, completion_request: CompletionRequest) -> Result<WithMetadata<CompletionResponse>> {
+        self.execute_json_with_metadata::<CompletionResponse>(ApiRequest::post("completions").json(&completion_request)?).await
+    }
+
+    /// Like [`create_completion`](Context::create_completion), but returns the completion in chunks as it is generated.
+    /// The `stream` field of the request is ignored
+    pub async fn create_completion_streamed(&self, completion_request: CompletionRequest) -> Result<impl Stream<Item = Result<CompletionDeltaResponse>> + Send + Unpin> {
+        Ok(self.create_completion_streamed_with_metadata(completion_request).await?.data)
+    }
+
+    /// Like [`create_completion_streamed`](Context::create_completion_streamed), along with the metadata of the
+    /// response, which is available before the first chunk
+    pub async fn create_completion_streamed_with_metadata(&self, mut completion_request: CompletionRequest) -> Result<WithMetadata<impl Stream<Item = Result<CompletionDeltaResponse>> + Send + Unpin>> {
+        completion_request.stream = Some(true);
+        let (response, metadata) = self.execute_with_metadata(ApiRequest::post("completions").json(&completion_request)?).await?;
+        Ok(WithMetadata { data: CompletionStream::<CompletionDeltaResponse>::new(response), metadata })
     }
 }
\ No newline at end of file
@@ -0,0 +1,293 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, time::Duration};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{auth::{Credential, EnvKey, KeyFile}, azure::AzureConfig, context::{Context, ContextBuilder}, error::{Error, Result}, retry::RetryPolicy, util::parse_duration};
+
+/// Settings for a [`Context`], read from the environment or from a profile in a config file
+///
+/// Profile files map profile names to these settings, e.g. in TOML:
+///
+/// ```toml
+/// [default]
+/// api_key_env = "OPENAI_API_KEY"
+/// timeout = "30s"
+///
+/// [azure]
+/// api_key_file = "/run/secrets/azure-openai-key"
+/// base_url = "https://my-resource.openai.azure.com"
+/// azure = { api_version = "2024-10-21", deployments = { "gpt-4o" = "chat-prod" } }
+/// ```
+///
+/// Durations are given in seconds or in the format of the `x-ratelimit-reset-*` headers, e.g. `1m30s` or `500ms`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContextConfig {
+    pub api_key: Option<String>,
+    /// Environment variable to read the API key from before every request
+    pub api_key_env: Option<String>,
+    /// File to read the API key from before every request
+    pub api_key_file: Option<PathBuf>,
+    pub org_id: Option<String>,
+    pub project_id: Option<String>,
+    /// Scheme and host of the API server. A path, as in `https://api.openai.com/v1`, is used as the path prefix
+    /// unless `path_prefix` is set
+    pub base_url: Option<String>,
+    pub path_prefix: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub connect_timeout: Option<Duration>,
+    pub max_retries: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub initial_backoff: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub max_backoff: Option<Duration>,
+    pub azure: Option<AzureConfig>,
+}
+
+/// Seconds as a number, or a duration such as `1m30s`
+fn parse_config_duration(value: &str) -> Option<Duration> {
+    match value.trim().parse::<f64>() {
+        Ok(seconds) => Duration::try_from_secs_f64(seconds).ok(),
+        Err(_) => parse_duration(value),
+    }
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Seconds(f64),
+        Text(String),
+    }
+
+    let duration = match Value::deserialize(deserializer)? {
+        Value::Seconds(seconds) => Duration::try_from_secs_f64(seconds).ok(),
+        Value::Text(ref text) => parse_config_duration(text),
+    };
+    duration
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom("invalid duration, expected seconds or e.g. `1m30s`"))
+}
+
+impl ContextConfig {
+    /// Read the settings from `OPENAI_API_KEY`, `OPENAI_ORG_ID`, `OPENAI_PROJECT_ID`, `OPENAI_BASE_URL`,
+    /// `OPENAI_TIMEOUT`, `OPENAI_CONNECT_TIMEOUT`, `OPENAI_MAX_RETRIES`, `OPENAI_INITIAL_BACKOFF` and
+    /// `OPENAI_MAX_BACKOFF`. Empty variables count as unset
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    pub(crate) fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let var = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+        let mut problems = Vec::new();
+        let mut duration = |name: &str| var(name).and_then(|value| {
+            let duration = parse_config_duration(&value);
+            if duration.is_none() {
+                problems.push(format!("{name} must be seconds or a duration such as `1m30s`, not `{value}`"));
+            }
+            duration
+        });
+
+        let timeout = duration("OPENAI_TIMEOUT");
+        let connect_timeout = duration("OPENAI_CONNECT_TIMEOUT");
+        let initial_backoff = duration("OPENAI_INITIAL_BACKOFF");
+        let max_backoff = duration("OPENAI_MAX_BACKOFF");
+        let max_retries = var("OPENAI_MAX_RETRIES").and_then(|value| {
+            let max_retries = value.trim().parse::<u32>().ok();
+            if max_retries.is_none() {
+                problems.push(format!("OPENAI_MAX_RETRIES must be a non-negative integer, not `{value}`"));
+            }
+            max_retries
+        });
+
+        let api_key = var("OPENAI_API_KEY");
+        if api_key.is_none() {
+            problems.push("OPENAI_API_KEY is not set".to_string());
+        }
+
+        let config = Self {
+            api_key,
+            org_id: var("OPENAI_ORG_ID"),
+            project_id: var("OPENAI_PROJECT_ID"),
+            base_url: var("OPENAI_BASE_URL"),
+            timeout,
+            connect_timeout,
+            max_retries,
+            initial_backoff,
+            max_backoff,
+            ..Self::default()
+        };
+        // The key was checked above, and the environment has no other way to set it
+        problems.extend(config.setting_problems());
+
+        match problems.is_empty() {
+            true => Ok(config),
+            false => Err(Error::Config(format!("Invalid environment: {}", problems.join("; ")))),
+        }
+    }
+
+    /// Read a profile from a `.toml` or `.json` file of named profiles
+    pub fn from_file(path: impl AsRef<Path>, profile: &str) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Could not read {}: {e}", path.display())))?;
+
+        let mut profiles = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str::<HashMap<String, Self>>(&text)
+                .map_err(|e| Error::Config(format!("Could not parse {}: {e}", path.display())))?,
+            Some("json") => serde_json::from_str::<HashMap<String, Self>>(&text)
+                .map_err(|e| Error::Config(format!("Could not parse {}: {e}", path.display())))?,
+            _ => return Err(Error::Config(format!("{} is neither a .toml nor a .json file", path.display()))),
+        };
+
+        let Some(config) = profiles.remove(profile) else {
+            let mut available = profiles.into_keys().collect::<Vec<_>>();
+            available.sort();
+            return Err(Error::Config(format!("{} has no profile `{profile}`. Available profiles: {}", path.display(), available.join(", "))));
+        };
+
+        let problems = config.problems();
+        match problems.is_empty() {
+            true => Ok(config),
+            false => Err(Error::Config(format!("Invalid profile `{profile}` in {}: {}", path.display(), problems.join("; ")))),
+        }
+    }
+
+    /// Everything wrong with these settings
+    fn problems(&self) -> Vec<String> {
+        let mut problems = self.key_problems();
+        problems.extend(self.setting_problems());
+        problems
+    }
+
+    /// Everything wrong with how the API key is given
+    fn key_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        match [self.api_key.is_some(), self.api_key_env.is_some(), self.api_key_file.is_some()].iter().filter(|set| **set).count() {
+            0 => problems.push("no API key, set one of `api_key`, `api_key_env` or `api_key_file`".to_string()),
+            1 => {},
+            _ => problems.push("only one of `api_key`, `api_key_env` and `api_key_file` may be set".to_string()),
+        }
+        if let Some(ref var) = self.api_key_env {
+            if std::env::var(var).map_or(true, |key| key.trim().is_empty()) {
+                problems.push(format!("environment variable {var} from `api_key_env` is not set"));
+            }
+        }
+        if let Some(ref path) = self.api_key_file {
+            if !path.is_file() {
+                problems.push(format!("`api_key_file` {} does not exist", path.display()));
+            }
+        }
+
+        problems
+    }
+
+    /// Everything wrong with the settings other than the API key
+    fn setting_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if let Some(ref base_url) = self.base_url {
+            if !(base_url.starts_with("http://") || base_url.starts_with("https://")) {
+                problems.push(format!("`base_url` must start with http:// or https://, not `{base_url}`"));
+            }
+        }
+        for (name, duration) in [("timeout", self.timeout), ("connect_timeout", self.connect_timeout)] {
+            if duration == Some(Duration::ZERO) {
+                problems.push(format!("`{name}` must be longer than zero"));
+            }
+        }
+        if let (Some(initial_backoff), Some(max_backoff)) = (self.initial_backoff, self.max_backoff) {
+            if initial_backoff > max_backoff {
+                problems.push("`initial_backoff` must not be longer than `max_backoff`".to_string());
+            }
+        }
+        if self.azure.is_some() {
+            if self.base_url.is_none() {
+                problems.push("`azure` requires the `base_url` of the Azure resource".to_string());
+            }
+            if self.org_id.is_some() || self.project_id.is_some() {
+                problems.push("`org_id` and `project_id` are not supported by `azure`".to_string());
+            }
+        }
+
+        problems
+    }
+
+    /// A builder with these settings, to which more can be added
+    pub fn builder(self) -> ContextBuilder {
+        let mut builder = ContextBuilder::default();
+        if let Some(api_key) = self.api_key {
+            builder = builder.auth(Credential::ApiKey(api_key));
+        } else if let Some(var) = self.api_key_env {
+            builder = builder.auth(EnvKey::new(var));
+        } else if let Some(path) = self.api_key_file {
+            builder = builder.auth(KeyFile::new(path));
+        }
+
+        if let Some(org_id) = self.org_id {
+            builder = builder.org_id(org_id);
+        }
+        if let Some(project_id) = self.project_id {
+            builder = builder.project_id(project_id);
+        }
+        if let Some(base_url) = self.base_url {
+            // Split `https://host/v1` into the base URL and the path prefix. `https://host/` has no path and keeps the
+            // default prefix
+            let path_start = base_url.find("://").map(|scheme_end| scheme_end + 3).and_then(|host_start| base_url[host_start..].find('/').map(|path| host_start + path));
+            match path_start {
+                Some(path_start) if base_url[path_start..].trim_matches('/').is_empty() => {
+                    builder = builder.base_url(&base_url[..path_start]);
+                },
+                Some(path_start) if self.path_prefix.is_none() => {
+                    builder = builder.base_url(&base_url[..path_start]).path_prefix(&base_url[path_start..]);
+                },
+                _ => builder = builder.base_url(base_url),
+            }
+        }
+        if let Some(path_prefix) = self.path_prefix {
+            builder = builder.path_prefix(path_prefix);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if let Some(azure) = self.azure {
+            builder = builder.azure(azure);
+        }
+
+        let mut retry_policy = RetryPolicy::default();
+        if let Some(max_retries) = self.max_retries {
+            retry_policy.max_retries = max_retries;
+        }
+        if let Some(initial_backoff) = self.initial_backoff {
+            retry_policy.initial_backoff = initial_backoff;
+        }
+        if let Some(max_backoff) = self.max_backoff {
+            retry_policy.max_backoff = max_backoff;
+        }
+        builder.retry_policy(retry_policy)
+    }
+
+    pub fn build(self) -> Result<Context> {
+        Ok(self.builder().build()?)
+    }
+}
+
+impl Context {
+    /// Context configured through `OPENAI_*` environment variables, see [`ContextConfig::from_env`]
+    pub fn from_env() -> Result<Self> {
+        ContextConfig::from_env()?.build()
+    }
+
+    /// Context configured by a profile in a `.toml` or `.json` file, see [`ContextConfig`]
+    pub fn from_profile(path: impl AsRef<Path>, profile: &str) -> Result<Self> {
+        ContextConfig::from_file(path, profile)?.build()
+    }
+}
@@ -1,34 +1,400 @@
-use reqwest::RequestBuilder;
+use std::{sync::Arc, time::{Duration, Instant}};
 
+use derive_builder::Builder;
+use reqwest::{Client, Proxy, RequestBuilder, Response, header::{HeaderMap, HeaderName, HeaderValue}};
+use serde::de::DeserializeOwned;
+
+use crate::{auth::{AuthProvider, Credential}, azure::AzureConfig, error::{Error, Result}, instrument::RequestTrace, metadata::{ResponseMetadata, WithMetadata}, middleware::{Middleware, OutgoingRequest, ReceivedResponse}, rate_limit::{self, RateLimiter, RateLimitPermit, StreamPermit}, request::{ApiRequest, RequestBody}, retry::RetryPolicy};
+
+pub(crate) const DEFAULT_BASE_URL: &str = "https://api.openai.com";
+pub(crate) const DEFAULT_PATH_PREFIX: &str = "/v1";
+
+/// Settings for the HTTP client shared by all requests made through a [`Context`]
+#[derive(Debug, Clone, Default)]
+pub struct HttpClientConfig {
+    client: Option<Client>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    proxy: Option<Proxy>,
+    user_agent: Option<String>,
+    default_headers: HeaderMap,
+}
+
+impl HttpClientConfig {
+    fn build(self) -> std::result::Result<Client, String> {
+        if let Some(client) = self.client {
+            return Ok(client);
+        }
+
+        let mut builder = Client::builder().default_headers(self.default_headers);
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if let Some(proxy) = self.proxy {
+            builder = builder.proxy(proxy);
+        }
+        if let Some(user_agent) = self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+
+        builder.build().map_err(|e| format!("Could not build HTTP client: {e}"))
+    }
+}
+
+#[derive(Debug, Clone, Builder)]
+#[builder(pattern = "owned")]
 pub struct Context {
-    api_key: String,
-    org_id: Option<String>
+    /// Where the credentials of each request come from. Set with [`api_key`](ContextBuilder::api_key) or
+    /// [`auth`](ContextBuilder::auth)
+    #[builder(setter(custom))]
+    auth: Arc<dyn AuthProvider>,
+    #[builder(setter(into, strip_option), default)]
+    org_id: Option<String>,
+    /// Sent in the `OpenAI-Project` header, for keys that can access several projects
+    #[builder(setter(into, strip_option), default)]
+    project_id: Option<String>,
+    /// Scheme and host of the API server, e.g. `http://localhost:8080`
+    #[builder(setter(into), default = "DEFAULT_BASE_URL.to_string()")]
+    base_url: String,
+    /// Path prepended to every endpoint, e.g. `/v1`. May be empty
+    #[builder(setter(into), default = "DEFAULT_PATH_PREFIX.to_string()")]
+    path_prefix: String,
+    /// Connection pool reused by every request
+    #[builder(setter(custom), field(type = "HttpClientConfig", build = "self.client.build()?"))]
+    client: Client,
+    /// How failed requests are retried. Defaults to [`RetryPolicy::default`]
+    #[builder(default)]
+    retry_policy: RetryPolicy,
+    /// Optional client-side limiter, shared by all clones of this context
+    #[builder(setter(into, strip_option), default)]
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Talk to Azure OpenAI at `base_url`, e.g. `https://my-resource.openai.azure.com`. `path_prefix` and `org_id`
+    /// are ignored
+    #[builder(setter(into, strip_option), default)]
+    azure: Option<AzureConfig>,
+    /// Hooks run around every request, in order
+    #[builder(setter(custom), default)]
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl From<ContextBuilderError> for Error {
+    fn from(e: ContextBuilderError) -> Self {
+        Error::Builder(e.to_string())
+    }
 }
 
-pub(crate) const API_URL: &str = "https://api.openai.com";
+impl ContextBuilder {
+    /// Send the same API key with every request
+    pub fn api_key(self, api_key: impl Into<String>) -> Self {
+        self.auth(Credential::ApiKey(api_key.into()))
+    }
+
+    pub fn auth(mut self, provider: impl AuthProvider + 'static) -> Self {
+        self.auth = Some(Arc::new(provider));
+        self
+    }
+
+    /// Add a hook around every request. Middleware runs in the order it was added
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.get_or_insert_with(Vec::new).push(Arc::new(middleware));
+        self
+    }
+
+    /// Share a provider, and any state it keeps, between contexts
+    pub fn shared_auth(mut self, provider: Arc<dyn AuthProvider>) -> Self {
+        self.auth = Some(provider);
+        self
+    }
+
+    /// Use a preconfigured client. Overrides all other HTTP client settings on this builder
+    pub fn http_client(mut self, client: Client) -> Self {
+        self.client.client = Some(client);
+        self
+    }
+
+    /// Total timeout for each request, from connecting until the response body has been read
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.client.timeout = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.client.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.client.proxy = Some(proxy);
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.client.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Header sent with every request
+    pub fn default_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.client.default_headers.insert(name, value);
+        self
+    }
+}
 
 impl Context {
     pub fn new(api_key: String) -> Self {
-        Context {
-            api_key,
-            org_id: None,
-        }
+        ContextBuilder::default()
+            .api_key(api_key)
+            .build()
+            .expect("All other Context fields have defaults")
     }
 
     pub fn new_with_org(api_key: String, org_id: String) -> Self {
-        Context {
-            api_key,
-            org_id: Some(org_id),
+        ContextBuilder::default()
+            .api_key(api_key)
+            .org_id(org_id)
+            .build()
+            .expect("All other Context fields have defaults")
+    }
+
+    /// Context for an Azure OpenAI resource, e.g. `https://my-resource.openai.azure.com`
+    pub fn new_azure(api_key: String, base_url: String, config: AzureConfig) -> Self {
+        ContextBuilder::default()
+            .api_key(api_key)
+            .base_url(base_url)
+            .azure(config)
+            .build()
+            .expect("All other Context fields have defaults")
+    }
+
+    pub fn auth(&self) -> &Arc<dyn AuthProvider> {
+        &self.auth
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn path_prefix(&self) -> &str {
+        &self.path_prefix
+    }
+
+    pub fn http_client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn rate_limiter(&self) -> Option<&Arc<RateLimiter>> {
+        self.rate_limiter.as_ref()
+    }
+
+    pub fn azure(&self) -> Option<&AzureConfig> {
+        self.azure.as_ref()
+    }
+
+    pub fn middleware(&self) -> &[Arc<dyn Middleware>] {
+        &self.middleware
+    }
+
+    /// Full URL of an endpoint, given its path relative to the prefix (e.g. `chat/completions`) and the model of the
+    /// request, which selects the deployment on Azure
+    pub(crate) fn endpoint(&self, path: &str, model: Option<&str>) -> String {
+        let prefix = self.path_prefix.trim_matches('/');
+        let base_url = self.base_url.trim_end_matches('/');
+        let path = path.trim_start_matches('/');
+
+        if let Some(ref azure) = self.azure {
+            format!("{base_url}/{}?api-version={}", azure.path(path, model), azure.api_version)
+        } else if prefix.is_empty() {
+            format!("{base_url}/{path}")
+        } else {
+            format!("{base_url}/{prefix}/{path}")
+        }
+    }
+
+    /// The request to send, without credentials
+    fn build_http_request(&self, request: &ApiRequest, attempt: u32) -> (RequestBuilder, OutgoingRequest) {
+        let mut outgoing = OutgoingRequest {
+            method: request.method.clone(),
+            endpoint: request.path.clone(),
+            url: self.endpoint(&request.path, rate_limit::request_model(request)),
+            body: match request.body {
+                RequestBody::Json(ref json) => Some(json.clone()),
+                _ => None,
+            },
+            headers: HeaderMap::new(),
+            attempt,
+        };
+        for middleware in &self.middleware {
+            middleware.before_send(&mut outgoing);
+        }
+
+        let builder = self.client.request(outgoing.method.clone(), &outgoing.url).headers(outgoing.headers.clone());
+        let builder = match (&request.body, &outgoing.body) {
+            (RequestBody::Multipart(form), _) => builder.multipart(form.to_form()),
+            (_, Some(json)) => builder.json(json),
+            (_, None) => builder,
+        };
+        (builder, outgoing)
+    }
+
+    /// Send a request, retrying according to the retry policy and turning non-success statuses into [`Error::Api`]
+    pub(crate) async fn execute_with_metadata(&self, request: ApiRequest) -> Result<(Response, ResponseMetadata)> {
+        let trace = RequestTrace::new(&request);
+        let started = Instant::now();
+        let (mut response, permit, retries) = self.execute_traced(request, &trace).await?;
+        let metadata = ResponseMetadata::from_response(&response, retries, started.elapsed());
+        // Streams take over the trace to record their chunks, and settle the permit once they report their usage
+        trace.finished();
+        response.extensions_mut().insert(trace);
+        if let (Some(limiter), Some(permit)) = (self.rate_limiter.as_ref(), permit) {
+            response.extensions_mut().insert(StreamPermit::new(limiter.clone(), permit));
+        }
+        Ok((response, metadata))
+    }
+
+    async fn execute_traced(&self, request: ApiRequest, trace: &RequestTrace) -> Result<(Response, Option<RateLimitPermit>, u32)> {
+        let result = trace.instrument(self.execute_limited(request, trace)).await;
+        if let Err(ref e) = result {
+            trace.failed(e);
+        }
+        result
+    }
+
+    /// Returns the successful response along with the number of failed attempts before it
+    async fn execute_limited(&self, request: ApiRequest, trace: &RequestTrace) -> Result<(Response, Option<RateLimitPermit>, u32)> {
+        let model = rate_limit::request_model(&request);
+        let limiter = self.rate_limiter.as_ref().zip(model);
+        let estimated_tokens = if limiter.is_some() { rate_limit::estimate_tokens(&request) } else { 0 };
+
+        let mut attempt = 0;
+        loop {
+            let (builder, outgoing) = self.build_http_request(&request, attempt);
+            let permit = match limiter {
+                Some((limiter, model)) => Some(limiter.acquire(model, estimated_tokens).await),
+                None => None,
+            };
+
+            // Fetched after waiting for the rate limiter, so that short-lived tokens cannot expire in the queue
+            let credential = match self.auth.credential().await {
+                Ok(credential) => credential,
+                Err(e) => {
+                    if let (Some((limiter, _)), Some(permit)) = (limiter, permit) {
+                        limiter.reconcile(permit, 0);
+                    }
+                    trace.attempts(attempt);
+                    return Err(e);
+                },
+            };
+            let builder = self.with_auth(builder, &credential);
+
+            let sent_at = Instant::now();
+            let response = builder.send().await.map_err(Error::from);
+            if let Ok(ref response) = response {
+                self.auth.observe(&credential, response.status(), response.headers());
+                trace.response(response.status(), response.headers());
+            }
+            if !self.middleware.is_empty() {
+                let received = response.as_ref().map(|response| ReceivedResponse {
+                    status: response.status(),
+                    headers: response.headers(),
+                    elapsed: sent_at.elapsed(),
+                });
+                for middleware in &self.middleware {
+                    middleware.after_receive(&outgoing, received.as_ref().map_err(|e| *e));
+                }
+            }
+            if let (Some((limiter, model)), Ok(ref response)) = (limiter, &response) {
+                limiter.observe_headers(model, response.headers());
+            }
+
+            let response = match response {
+                Ok(response) if response.status().is_success() => {
+                    trace.attempts(attempt);
+                    return Ok((response, permit, attempt));
+                },
+                response => response,
+            };
+
+            // Rejected requests don't consume tokens
+            if let (Some((limiter, _)), Some(permit)) = (limiter, permit) {
+                limiter.reconcile(permit, 0);
+            }
+
+            let (error, headers) = match response {
+                Ok(response) => {
+                    let status = response.status();
+                    let headers = response.headers().clone();
+                    let body = match response.text().await {
+                        Ok(body) => body,
+                        Err(e) => {
+                            trace.attempts(attempt);
+                            return Err(e.into());
+                        },
+                    };
+                    (Error::from_response(status, body), Some(headers))
+                },
+                Err(e) => (e, None),
+            };
+
+            if attempt >= self.retry_policy.max_retries || !self.retry_policy.is_retryable(&error, headers.as_ref()) {
+                trace.attempts(attempt);
+                return Err(error);
+            }
+
+            let delay = self.retry_policy.delay(attempt, &error, headers.as_ref());
+            trace.retry(attempt, &error, delay);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
         }
     }
 
-    pub(crate) fn with_auth(&self, builder: RequestBuilder) -> RequestBuilder {
-        (
-            if let Some(ref org_id) = self.org_id {
-                builder.header("OpenAI-Organization", org_id)
-            } else {
-                builder
+    pub(crate) async fn execute_json_with_metadata<T: DeserializeOwned>(&self, request: ApiRequest) -> Result<WithMetadata<T>> {
+        let trace = RequestTrace::new(&request);
+        let started = Instant::now();
+        let (response, permit, retries) = self.execute_traced(request, &trace).await?;
+        let metadata = ResponseMetadata::from_response(&response, retries, started.elapsed());
+        let body = match trace.instrument(response.bytes()).await {
+            Ok(body) => body,
+            Err(e) => {
+                let e = Error::from(e);
+                trace.failed(&e);
+                return Err(e);
+            },
+        };
+        trace.usage(&body);
+        trace.finished();
+
+        if let (Some(limiter), Some(permit)) = (self.rate_limiter.as_ref(), permit) {
+            if let Some(tokens) = rate_limit::reported_usage(&body).and_then(|usage| usage.total()) {
+                limiter.reconcile(permit, tokens);
             }
-        ).bearer_auth(&self.api_key)
+        }
+
+        Ok(WithMetadata { data: serde_json::from_slice(&body)?, metadata })
+    }
+
+    pub(crate) fn with_auth(&self, builder: RequestBuilder, credential: &Credential) -> RequestBuilder {
+        if self.azure.is_some() {
+            // Azure has neither organizations nor projects
+            return match credential {
+                Credential::ApiKey(key) => builder.header("api-key", key),
+                Credential::BearerToken(token) => builder.bearer_auth(token),
+            };
+        }
+
+        let mut builder = builder;
+        if let Some(ref org_id) = self.org_id {
+            builder = builder.header("OpenAI-Organization", org_id);
+        }
+        if let Some(ref project_id) = self.project_id {
+            builder = builder.header("OpenAI-Project", project_id);
+        }
+        builder.bearer_auth(credential.secret())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,216 @@
+use std::{collections::BTreeSet, ops::Range};
+
+use derive_builder::Builder;
+
+use crate::{chat::{ChatHistoryBuilder, ChatMessage, Role}, context::Context, error::Result, tokenizer::{Encoding, Tokenizer}};
+
+/// Context window sizes in tokens, by model name prefix. More specific prefixes come first
+const CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("gpt-5", 400_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4.5", 128_000),
+    ("gpt-4o", 128_000),
+    ("chatgpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-1106", 128_000),
+    ("gpt-4-0125", 128_000),
+    ("gpt-4-vision", 128_000),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo-instruct", 4_096),
+    ("gpt-3.5-turbo-0301", 4_096),
+    ("gpt-3.5-turbo-0613", 4_096),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1-mini", 128_000),
+    ("o1-preview", 128_000),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4", 200_000),
+    ("text-davinci-00", 4_097),
+];
+
+const SUMMARY_PROMPT: &str = "Summarize the following conversation in a few sentences. \
+    Keep names, facts, decisions and open questions, and leave out pleasantries.";
+
+/// Number of tokens a model can attend to, shared between the prompt and the completion
+pub fn context_window(model: &str) -> Option<usize> {
+    let model = model.strip_prefix("ft:").unwrap_or(model);
+    CONTEXT_WINDOWS.iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, window)| *window)
+}
+
+/// How to make room when the messages do not fit the context window
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContextWindowStrategy {
+    /// Drop the oldest messages until the rest fits
+    DropOldest,
+    /// Keep only the last `n` turns, each starting with a user message, and drop older messages if that still does not fit
+    KeepLastTurns(usize),
+    /// Replace the oldest messages with a summary written by `model`, of at most `max_summary_tokens`
+    Summarize {
+        model: String,
+        max_summary_tokens: usize,
+    },
+}
+
+/// Fits chat requests into their model's context window
+///
+/// System messages, pinned messages and the last message are never removed. An assistant message calling tools is
+/// removed together with the results of those calls, so that the remaining history stays valid.
+#[derive(Debug, Clone, Builder)]
+#[builder(pattern = "owned")]
+pub struct ContextWindowManager {
+    #[builder(default = "ContextWindowStrategy::DropOldest")]
+    pub strategy: ContextWindowStrategy,
+    /// Context window to fit into, instead of the one known for the request's model
+    #[builder(setter(strip_option), default)]
+    pub max_context_tokens: Option<usize>,
+    /// Tokens left for the completion when the request does not set `max_tokens`
+    #[builder(default = "1000")]
+    pub reserved_completion_tokens: usize,
+    /// Indices of messages that must be kept
+    #[builder(setter(custom), default)]
+    pub pinned: BTreeSet<usize>,
+}
+
+impl ContextWindowManagerBuilder {
+    pub fn pin(mut self, index: usize) -> Self {
+        self.pinned.get_or_insert_with(BTreeSet::new).insert(index);
+        self
+    }
+}
+
+impl Default for ContextWindowManager {
+    fn default() -> Self {
+        ContextWindowManagerBuilder::default().build().expect("All ContextWindowManager fields have defaults")
+    }
+}
+
+impl ContextWindowManager {
+    pub fn pin(&mut self, index: usize) {
+        self.pinned.insert(index);
+    }
+
+    pub fn unpin(&mut self, index: usize) {
+        self.pinned.remove(&index);
+    }
+}
+
+/// Split messages into groups that must be kept or removed together
+fn message_groups(messages: &[ChatMessage]) -> Vec<Range<usize>> {
+    let mut groups: Vec<Range<usize>> = Vec::new();
+    for (index, message) in messages.iter().enumerate() {
+        let is_result = matches!(message.role, Role::Tool | Role::Function);
+        match groups.last_mut() {
+            // Results belong to the call preceding them
+            Some(group) if is_result => group.end = index + 1,
+            _ => groups.push(index..index + 1),
+        }
+    }
+    groups
+}
+
+impl Context {
+    /// Apply `manager`'s strategy to a chat request whose messages do not fit its model's context window.
+    /// Requests that fit, or whose model's context window is not known, are returned unchanged
+    pub async fn fit_context_window(&self, chat_completion_request: ChatHistoryBuilder, manager: &ContextWindowManager) -> Result<ChatHistoryBuilder> {
+        let history = chat_completion_request.clone().build()?;
+        let Some(window) = manager.max_context_tokens.or_else(|| context_window(&history.model)) else {
+            return Ok(chat_completion_request);
+        };
+
+        let completion_tokens = history.max_tokens.map(|max_tokens| max_tokens as usize).unwrap_or(manager.reserved_completion_tokens);
+        let tokenizer = Tokenizer::for_model(&history.model).unwrap_or_else(|| Encoding::Cl100kBase.tokenizer());
+
+        // Function and tool declarations are part of the prompt as well
+        let mut budget = window.saturating_sub(completion_tokens + tokenizer.count_messages(&[]));
+        for declarations in [serde_json::to_string(&history.functions)?, serde_json::to_string(&history.tools)?] {
+            budget = budget.saturating_sub(tokenizer.count(&declarations));
+        }
+
+        let messages = history.messages;
+        let groups = message_groups(&messages);
+        let group_tokens = groups.iter()
+            .map(|group| tokenizer.count_messages(&messages[group.clone()]) - tokenizer.count_messages(&[]))
+            .collect::<Vec<_>>();
+        if group_tokens.iter().sum::<usize>() <= budget {
+            return Ok(chat_completion_request.messages(messages));
+        }
+
+        let protected = groups.iter().enumerate()
+            .map(|(index, group)| index == groups.len() - 1
+                || group.clone().any(|message| manager.pinned.contains(&message) || matches!(messages[message].role, Role::System)))
+            .collect::<Vec<_>>();
+        let mut keep = vec![true; groups.len()];
+
+        if let ContextWindowStrategy::KeepLastTurns(turns) = manager.strategy {
+            let turn_starts = groups.iter().enumerate()
+                .filter(|(_, group)| matches!(messages[group.start].role, Role::User))
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+            let first_kept = turn_starts.len().checked_sub(turns).map(|turn| turn_starts.get(turn).copied().unwrap_or(groups.len()));
+            if let Some(first_kept) = first_kept {
+                keep[..first_kept].copy_from_slice(&protected[..first_kept]);
+            }
+        }
+
+        if let ContextWindowStrategy::Summarize { max_summary_tokens, .. } = manager.strategy {
+            budget = budget.saturating_sub(max_summary_tokens + tokenizer.count_messages(&[ChatMessage::new(Role::System, "", None)]));
+        }
+
+        let mut tokens = group_tokens.iter().zip(&keep).filter(|(_, keep)| **keep).map(|(tokens, _)| tokens).sum::<usize>();
+        for group in 0..groups.len() {
+            if tokens <= budget {
+                break;
+            }
+            if keep[group] && !protected[group] {
+                keep[group] = false;
+                tokens -= group_tokens[group];
+            }
+        }
+
+        let Some(first_elided) = keep.iter().position(|keep| !keep) else {
+            return Ok(chat_completion_request.messages(messages));
+        };
+
+        let elided = groups.iter().zip(&keep)
+            .filter(|(_, keep)| !**keep)
+            .flat_map(|(group, _)| messages[group.clone()].iter().cloned())
+            .collect::<Vec<_>>();
+        let mut kept = Vec::new();
+        for (group, keep) in groups.iter().zip(&keep) {
+            if group.start == groups[first_elided].start {
+                if let ContextWindowStrategy::Summarize { ref model, max_summary_tokens } = manager.strategy {
+                    if let Some(summary) = self.summarize(model, max_summary_tokens, &elided).await? {
+                        kept.push(ChatMessage::new(Role::System, format!("Summary of the earlier conversation: {summary}"), None));
+                    }
+                }
+            }
+            if *keep {
+                kept.extend(messages[group.clone()].iter().cloned());
+            }
+        }
+
+        Ok(chat_completion_request.messages(kept))
+    }
+
+    async fn summarize(&self, model: &str, max_summary_tokens: usize, messages: &[ChatMessage]) -> Result<Option<String>> {
+        let transcript = messages.iter()
+            .filter_map(|message| message.content.as_ref().map(|content| format!("{}: {}", message.role.as_str(), content.to_text())))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        let response = self.create_chat_completion_sync(
+            ChatHistoryBuilder::default()
+                .model(model)
+                .max_tokens(max_summary_tokens as u64)
+                .messages(vec![
+                    ChatMessage::new(Role::System, SUMMARY_PROMPT, None),
+                    ChatMessage::new(Role::User, transcript, None),
+                ])
+        ).await?;
+
+        Ok(response.choices.into_iter().next().and_then(|choice| choice.message.content).map(|summary| summary.to_text()).filter(|summary| !summary.is_empty()))
+    }
+}
@@ -0,0 +1,187 @@
+use std::{pin::Pin, task::Poll};
+
+use futures::Stream;
+
+use crate::{accumulator::{AccumulatingStream, ChatCompletionStreamExt}, chat::{ChatCompletionDeltaResponse, ChatCompletionSyncResponse, ChatHistory, ChatHistoryBuilder, ChatMessage, Role}, context::Context, error::Result};
+#[cfg(feature = "tokenizer")]
+use crate::context_window::ContextWindowManager;
+
+/// A chat with a model that keeps its own history
+///
+/// Messages are only added to the history once the model has replied to them, so a failed request can simply be
+/// retried. Only the first choice of each reply is kept.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    context: Context,
+    /// Everything but the messages of each request
+    settings: ChatHistoryBuilder,
+    messages: Vec<ChatMessage>,
+    #[cfg(feature = "tokenizer")]
+    context_window: Option<ContextWindowManager>,
+}
+
+impl Conversation {
+    pub fn new(context: Context, model: impl Into<String>) -> Self {
+        Self::with_settings(context, ChatHistoryBuilder::default().model(model))
+    }
+
+    /// Start a conversation using the model and parameters of `settings`. Messages set on it are ignored
+    pub fn with_settings(context: Context, settings: ChatHistoryBuilder) -> Self {
+        Self {
+            context,
+            settings,
+            messages: Vec::new(),
+            #[cfg(feature = "tokenizer")]
+            context_window: None,
+        }
+    }
+
+    /// Continue the conversation held by a request
+    pub fn from_request(context: Context, request: ChatHistoryBuilder) -> Result<Self> {
+        let messages = request.clone().build()?.messages;
+        Ok(Self {
+            messages,
+            ..Self::with_settings(context, request)
+        })
+    }
+
+    pub fn system(mut self, prompt: impl Into<String>) -> Self {
+        self.messages.push(ChatMessage::new(Role::System, prompt, None));
+        self
+    }
+
+    /// Fit the history into the model's context window before each request. The history itself is kept as is
+    #[cfg(feature = "tokenizer")]
+    pub fn context_window(mut self, manager: ContextWindowManager) -> Self {
+        self.context_window = Some(manager);
+        self
+    }
+
+    pub fn messages(&self) -> &[ChatMessage] {
+        &self.messages
+    }
+
+    pub fn settings(&self) -> &ChatHistoryBuilder {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: ChatHistoryBuilder) {
+        self.settings = settings;
+    }
+
+    /// The request that would continue this conversation, without any new messages
+    pub fn to_history(&self) -> Result<ChatHistory> {
+        self.settings.clone().messages(self.messages.clone()).build().map_err(Into::into)
+    }
+
+    /// Add a message to the history without sending it
+    pub fn push(&mut self, message: ChatMessage) {
+        self.messages.push(message);
+    }
+
+    /// Keep a message when the history is fitted into the context window
+    #[cfg(feature = "tokenizer")]
+    pub fn pin(&mut self, index: usize) {
+        self.context_window.get_or_insert_with(Default::default).pin(index);
+    }
+
+    /// The most recent reply of the model
+    pub fn last_reply(&self) -> Option<&ChatMessage> {
+        self.messages.iter().rev().find(|message| matches!(message.role, Role::Assistant))
+    }
+
+    /// A new conversation with the first `index` messages of this one
+    pub fn fork(&self, index: usize) -> Self {
+        let mut fork = self.clone();
+        fork.messages.truncate(index);
+        #[cfg(feature = "tokenizer")]
+        if let Some(ref mut manager) = fork.context_window {
+            manager.pinned.retain(|pinned| *pinned < index);
+        }
+        fork
+    }
+
+    /// Remove the last user message and everything after it, returning the removed messages
+    pub fn undo(&mut self) -> Vec<ChatMessage> {
+        let start = self.messages.iter().rposition(|message| matches!(message.role, Role::User)).unwrap_or(self.messages.len());
+        #[cfg(feature = "tokenizer")]
+        if let Some(ref mut manager) = self.context_window {
+            manager.pinned.retain(|pinned| *pinned < start);
+        }
+        self.messages.split_off(start)
+    }
+
+    pub async fn send(&mut self, message: impl Into<String>) -> Result<ChatCompletionSyncResponse> {
+        self.send_messages(vec![ChatMessage::new(Role::User, message, None)]).await
+    }
+
+    /// Send any messages, such as the results of the tool calls in the last reply
+    pub async fn send_messages(&mut self, messages: Vec<ChatMessage>) -> Result<ChatCompletionSyncResponse> {
+        let request = self.request(&messages).await?;
+        let response = self.context.create_chat_completion_sync(request).await?;
+
+        self.messages.extend(messages);
+        if let Some(choice) = response.choices.first() {
+            self.messages.push(choice.message.clone());
+        }
+        Ok(response)
+    }
+
+    /// Like [`send`](Conversation::send), but streams the reply. It is added to the history once the stream has ended
+    pub async fn send_streamed(&mut self, message: impl Into<String>) -> Result<impl Stream<Item = Result<ChatCompletionDeltaResponse>> + Send + Unpin + '_> {
+        self.send_messages_streamed(vec![ChatMessage::new(Role::User, message, None)]).await
+    }
+
+    pub async fn send_messages_streamed(&mut self, messages: Vec<ChatMessage>) -> Result<impl Stream<Item = Result<ChatCompletionDeltaResponse>> + Send + Unpin + '_> {
+        let request = self.request(&messages).await?;
+        let stream = self.context.create_chat_completion_streamed(request).await?;
+
+        Ok(ConversationStream {
+            stream: stream.accumulate(),
+            history: &mut self.messages,
+            sent: messages,
+            done: false,
+        })
+    }
+
+    async fn request(&self, messages: &[ChatMessage]) -> Result<ChatHistoryBuilder> {
+        let request = self.settings.clone().messages([self.messages.as_slice(), messages].concat());
+        #[cfg(feature = "tokenizer")]
+        if let Some(ref manager) = self.context_window {
+            return self.context.fit_context_window(request, manager).await;
+        }
+        Ok(request)
+    }
+}
+
+/// Adds the sent messages and the reply to the history once the stream has ended without errors
+struct ConversationStream<'a, S> {
+    stream: AccumulatingStream<S>,
+    history: &'a mut Vec<ChatMessage>,
+    sent: Vec<ChatMessage>,
+    /// Set once the history has been updated, or must not be because of an error
+    done: bool,
+}
+
+impl<S> Stream for ConversationStream<'_, S>
+where
+    S: Stream<Item = Result<ChatCompletionDeltaResponse>> + Unpin {
+    type Item = Result<ChatCompletionDeltaResponse>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.stream).poll_next(cx);
+        match poll {
+            Poll::Ready(Some(Err(_))) => this.done = true,
+            Poll::Ready(None) if !this.done => {
+                this.done = true;
+                this.history.append(&mut this.sent);
+                if let Some(choice) = this.stream.accumulator().response().choices.into_iter().next() {
+                    this.history.push(choice.message);
+                }
+            },
+            _ => {},
+        }
+        poll
+    }
+}
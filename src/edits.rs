@@ -1,8 +1,7 @@
 use derive_builder::Builder;
-use reqwest::Client;
 use serde::{Serialize, Deserialize};
 
-use crate::{completion::Usage, context::{API_URL, Context}};
+use crate::{metadata::WithMetadata, completion::Usage, context::Context, error::Result, request::ApiRequest, util::ExtraFields};
 
 #[derive(Debug, Serialize, Builder)]
 pub struct EditRequest {
@@ -28,26 +27,28 @@ pub struct EditRequest {
 pub struct Edit {
     pub text: String,
     pub index: i32,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct EditResponse {
-    /* pub object: "edit", */
+    #[serde(default)]
+    pub object: String,
     pub created: u64,
     pub choices: Vec<Edit>,
-    pub usage: Usage
+    pub usage: Usage,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Context {
-    pub async fn create_edit(&self, edit_request: EditRequest) -> anyhow::Result<EditResponse> {
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/edits")))
-                .json(&edit_request)
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<EditResponse>()
-                .await?
-        )
+    pub async fn create_edit(&self, edit_request: EditRequest) -> Result<EditResponse> {
+        Ok(self.create_edit_with_metadata(edit_request).await?.data)
+    }
+
+    /// Like [`create_edit`](Context::create_edit), along with the metadata of the response
+    pub async fn create_edit_with_metadata(&self, edit_request: EditRequest) -> Result<WithMetadata<EditResponse>> {
+        self.execute_json_with_metadata::<EditResponse>(ApiRequest::post("edits").json(&edit_request)?).await
     }
 }
\ No newline at end of file
@@ -1,8 +1,7 @@
 use derive_builder::Builder;
-use reqwest::Client;
 use serde::{Serialize, Deserialize};
 
-use crate::{completion::Sequence, context::{API_URL, Context}};
+use crate::{metadata::WithMetadata, completion::Sequence, context::Context, error::Result, request::ApiRequest, util::ExtraFields};
 
 #[derive(Debug, Serialize, Builder)]
 pub struct EmbeddingRequest {
@@ -17,35 +16,40 @@ pub struct EmbeddingRequest {
 
 #[derive(Debug, Deserialize)]
 pub struct Embedding {
-    /* pub object: "embedding", */
+    #[serde(default)]
+    pub object: String,
     pub embedding: Vec<f64>,
     pub index: u32,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct EmbeddingUsage {
     pub prompt_tokens: u64,
     pub total_tokens: u64,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct EmbeddingResponse {
-    /* pub object: "list", */
+    #[serde(default)]
+    pub object: String,
     pub data: Vec<Embedding>,
     pub model: String,
     pub usage: EmbeddingUsage,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Context {
-    pub async fn create_embedding(&self, embedding_request: EmbeddingRequest) -> anyhow::Result<EmbeddingResponse> {
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/embeddings")))
-                .json(&embedding_request)
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<EmbeddingResponse>()
-                .await?
-        )
+    pub async fn create_embedding(&self, embedding_request: EmbeddingRequest) -> Result<EmbeddingResponse> {
+        Ok(self.create_embedding_with_metadata(embedding_request).await?.data)
+    }
+
+    /// Like [`create_embedding`](Context::create_embedding), along with the metadata of the response
+    pub async fn create_embedding_with_metadata(&self, embedding_request: EmbeddingRequest) -> Result<WithMetadata<EmbeddingResponse>> {
+        self.execute_json_with_metadata::<EmbeddingResponse>(ApiRequest::post("embeddings").json(&embedding_request)?).await
     }
 }
\ No newline at end of file
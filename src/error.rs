@@ -0,0 +1,174 @@
+use std::fmt::Display;
+
+use eventsource_stream::EventStreamError;
+use reqwest::StatusCode;
+use serde::Deserialize;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Error object returned by the API in the body of a failed request
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiError {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_code")]
+    pub code: Option<String>,
+    pub param: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ApiErrorBody {
+    pub(crate) error: ApiError,
+}
+
+// Error codes are usually strings, but some endpoints send integers
+fn deserialize_code<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de> {
+    Ok(match Option::<serde_json::Value>::deserialize(deserializer)? {
+        Some(serde_json::Value::String(s)) => Some(s),
+        Some(serde_json::Value::Null) | None => None,
+        Some(other) => Some(other.to_string()),
+    })
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The API responded with a non-success status. `error` is populated if the body held an API error object
+    Api {
+        status: StatusCode,
+        error: Option<ApiError>,
+        body: String,
+    },
+    /// The request could not be sent or the response could not be read
+    Http(reqwest::Error),
+    /// The request could not be serialized, or the response body did not match the expected type
+    Deserialize(serde_json::Error),
+    /// A file to upload could not be read
+    Io(std::io::Error),
+    /// A request builder was missing a required field or held an invalid value
+    Builder(String),
+    /// Credentials could not be obtained from the context's [`AuthProvider`](crate::auth::AuthProvider)
+    Auth(String),
+    /// Settings from the environment or a config file were missing or invalid
+    Config(String),
+    /// A server-sent event stream was malformed
+    Stream(String),
+    /// The model declined to produce the requested output, with its explanation
+    Refusal(String),
+    /// The model's output did not match the expected type
+    InvalidOutput {
+        content: String,
+        error: serde_json::Error,
+    },
+}
+
+impl Error {
+    pub(crate) fn from_response(status: StatusCode, body: String) -> Self {
+        let error = serde_json::from_str::<ApiErrorBody>(&body).ok().map(|body| body.error);
+        Error::Api { status, error, body }
+    }
+
+    /// HTTP status of the failed request, if the server responded at all
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Api { status, .. } => Some(*status),
+            Error::Http(e) => e.status(),
+            _ => None,
+        }
+    }
+
+    pub fn api_error(&self) -> Option<&ApiError> {
+        match self {
+            Error::Api { error, .. } => error.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Value of `error.code` in the API response, e.g. `context_length_exceeded`
+    pub fn code(&self) -> Option<&str> {
+        self.api_error().and_then(|error| error.code.as_deref())
+    }
+
+    pub fn is_rate_limit(&self) -> bool {
+        self.status() == Some(StatusCode::TOO_MANY_REQUESTS) && !self.is_quota_exceeded()
+    }
+
+    /// The account has run out of credits. Retrying will not help
+    pub fn is_quota_exceeded(&self) -> bool {
+        self.code() == Some("insufficient_quota")
+    }
+
+    pub fn is_context_length_exceeded(&self) -> bool {
+        self.code() == Some("context_length_exceeded")
+    }
+
+    pub fn is_auth(&self) -> bool {
+        matches!(self, Error::Auth(_)) || matches!(self.status(), Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN))
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Api { status, error: Some(error), .. } => {
+                write!(f, "API error ({status}): {}", error.message)?;
+                if let Some(ref code) = error.code {
+                    write!(f, " [{code}]")?;
+                }
+                Ok(())
+            },
+            Error::Api { status, body, .. } => write!(f, "API error ({status}): {body}"),
+            Error::Http(e) => write!(f, "HTTP error: {e}"),
+            Error::Deserialize(e) => write!(f, "Could not deserialize response: {e}"),
+            Error::Builder(e) => write!(f, "Invalid request: {e}"),
+            Error::Auth(e) => write!(f, "Could not get credentials: {e}"),
+            Error::Config(e) => write!(f, "Invalid configuration: {e}"),
+            Error::Stream(e) => write!(f, "Invalid event stream: {e}"),
+            Error::Io(e) => write!(f, "IO error: {e}"),
+            Error::Refusal(refusal) => write!(f, "Model refused to respond: {refusal}"),
+            Error::InvalidOutput { error, .. } => write!(f, "Model output did not match the expected type: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            Error::Deserialize(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::InvalidOutput { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Deserialize(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<EventStreamError<reqwest::Error>> for Error {
+    fn from(e: EventStreamError<reqwest::Error>) -> Self {
+        match e {
+            EventStreamError::Transport(e) => Error::Http(e),
+            EventStreamError::Utf8(e) => Error::Stream(e.to_string()),
+            EventStreamError::Parser(e) => Error::Stream(e.to_string()),
+        }
+    }
+}
@@ -1,80 +1,82 @@
 use bytes::Bytes;
-use reqwest::{Client, multipart::Form};
+use futures::StreamExt;
 use serde::Deserialize;
 
-use crate::{context::{API_URL, Context}, util::{DataList, FileResource}};
+use crate::{metadata::WithMetadata, context::Context, error::{Error, Result}, request::ApiRequest, util::{DataList, ExtraFields, FileResource, MultipartForm}};
 
 #[derive(Debug, Deserialize)]
 pub struct FileInfo {
     pub id: String,
-    /* pub object: "file", */
+    #[serde(default)]
+    pub object: String,
     pub bytes: u64,
     pub created_at: u64,
     pub filename: String,
     pub purpose: String,
+    /// When the file expires, if it does
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct FileDeleteResponse {
     pub id: String,
-    /* pub object: "file", */
+    #[serde(default)]
+    pub object: String,
     pub deleted: bool,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Context {
-    pub async fn get_files(&self) -> anyhow::Result<Vec<FileInfo>> {
-        Ok(
-            self.with_auth(Client::builder().build()?.get(&format!("{API_URL}/v1/files")))
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<DataList<FileInfo>>()
-                .await?
-                .data
-        )
+    pub async fn get_files(&self) -> Result<Vec<FileInfo>> {
+        Ok(self.get_files_with_metadata().await?.data)
     }
 
-    pub async fn upload_file(&self, file: FileResource, file_name: String, purpose: String) -> anyhow::Result<FileInfo> {
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/files")))
-                .multipart(file.write_file_named(Form::new().text("purpose", purpose), "file", file_name))
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<FileInfo>()
-                .await?
-        )
+    /// Like [`get_files`](Context::get_files), along with the metadata of the response
+    pub async fn get_files_with_metadata(&self) -> Result<WithMetadata<Vec<FileInfo>>> {
+        Ok(self.execute_json_with_metadata::<DataList<FileInfo>>(ApiRequest::get("files")).await?.map(|list| list.data))
     }
 
-    pub async fn delete_file(&self, file_id: &str) -> anyhow::Result<FileDeleteResponse> {
-        Ok(
-            self.with_auth(Client::builder().build()?.delete(&format!("{API_URL}/v1/files/{file_id}")))
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<FileDeleteResponse>()
-                .await?
-        )
+    pub async fn upload_file(&self, file: FileResource, file_name: String, purpose: String) -> Result<FileInfo> {
+        Ok(self.upload_file_with_metadata(file, file_name, purpose).await?.data)
     }
 
-    pub async fn get_file(&self, file_id: &str) -> anyhow::Result<impl futures_core::Stream<Item = reqwest::Result<Bytes>>> {
-        Ok(
-            self.with_auth(Client::builder().build()?.get(&format!("{API_URL}/v1/files/{file_id}")))
-                .send()
-                .await?
-                .error_for_status()?
-                .bytes_stream()
-        )
+    /// Like [`upload_file`](Context::upload_file), along with the metadata of the response
+    pub async fn upload_file_with_metadata(&self, file: FileResource, file_name: String, purpose: String) -> Result<WithMetadata<FileInfo>> {
+        let form = file.write_file_named(MultipartForm::new().text("purpose", purpose), "file", file_name).await?;
+        self.execute_json_with_metadata::<FileInfo>(ApiRequest::post("files").multipart(form)).await
     }
 
-    pub async fn get_file_direct(&self, file_id: &str) -> anyhow::Result<Bytes> {
-        Ok(
-            self.with_auth(Client::builder().build()?.get(&format!("{API_URL}/v1/files/{file_id}")))
-                .send()
-                .await?
-                .error_for_status()?
-                .bytes()
-                .await?
-        )
+    pub async fn delete_file(&self, file_id: &str) -> Result<FileDeleteResponse> {
+        Ok(self.delete_file_with_metadata(file_id).await?.data)
+    }
+
+    /// Like [`delete_file`](Context::delete_file), along with the metadata of the response
+    pub async fn delete_file_with_metadata(&self, file_id: &str) -> Result<WithMetadata<FileDeleteResponse>> {
+        self.execute_json_with_metadata::<FileDeleteResponse>(ApiRequest::delete(format!("files/{file_id}"))).await
+    }
+
+    pub async fn get_file(&self, file_id: &str) -> Result<impl futures_core::Stream<Item = Result<Bytes>>> {
+        Ok(self.get_file_with_metadata(file_id).await?.data)
+    }
+
+    /// Like [`get_file`](Context::get_file), along with the metadata of the response, which is available before the
+    /// content has been downloaded
+    pub async fn get_file_with_metadata(&self, file_id: &str) -> Result<WithMetadata<impl futures_core::Stream<Item = Result<Bytes>>>> {
+        let (response, metadata) = self.execute_with_metadata(ApiRequest::get(format!("files/{file_id}"))).await?;
+        Ok(WithMetadata { data: response.bytes_stream().map(|chunk| chunk.map_err(Error::from)), metadata })
+    }
+
+    pub async fn get_file_direct(&self, file_id: &str) -> Result<Bytes> {
+        Ok(self.get_file_direct_with_metadata(file_id).await?.data)
+    }
+
+    /// Like [`get_file_direct`](Context::get_file_direct), along with the metadata of the response
+    pub async fn get_file_direct_with_metadata(&self, file_id: &str) -> Result<WithMetadata<Bytes>> {
+        let (response, metadata) = self.execute_with_metadata(ApiRequest::get(format!("files/{file_id}"))).await?;
+        Ok(WithMetadata { data: response.bytes().await?, metadata })
     }
 }
\ No newline at end of file
@@ -1,8 +1,7 @@
 use derive_builder::Builder;
-use reqwest::Client;
 use serde::{Serialize, Deserialize};
 
-use crate::{file::FileInfo, context::{API_URL, Context}, util::DataList};
+use crate::{metadata::WithMetadata, file::FileInfo, context::Context, error::Result, util::{DataList, ExtraFields}, request::ApiRequest};
 
 #[derive(Debug, Serialize, Builder)]
 pub struct CreateFineTuneRequest {
@@ -45,10 +44,13 @@ pub struct CreateFineTuneRequest {
 
 #[derive(Debug, Deserialize)]
 pub struct FineTuneEvent {
-    /* pub object: "fine-tune-event", */
+    #[serde(default)]
+    pub object: String,
     pub created_at: u64,
     pub level: String,
     pub message: String,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Deserialize)]
@@ -57,26 +59,38 @@ pub struct Hyperparams {
     pub learning_rate_multiplier: f64,
     pub prompt_loss_weight: f64,
     pub n_epochs: u32,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FineTuneStatus {
     Pending,
+    ValidatingFiles,
+    Queued,
+    Running,
     Succeeded,
+    Failed,
     Cancelled,
+    /// A status this version of the crate does not know about
+    Unknown(String),
 }
 
 impl<'de> Deserialize<'de> for FineTuneStatus {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
         match s.as_str() {
             "pending" => Ok(FineTuneStatus::Pending),
+            "validating_files" => Ok(FineTuneStatus::ValidatingFiles),
+            "queued" => Ok(FineTuneStatus::Queued),
+            "running" => Ok(FineTuneStatus::Running),
             "succeeded" => Ok(FineTuneStatus::Succeeded),
+            "failed" => Ok(FineTuneStatus::Failed),
             "cancelled" => Ok(FineTuneStatus::Cancelled),
-            _ => Err(serde::de::Error::custom(format!("Invalid fine-tune status: {}", s))),
+            _ => Ok(FineTuneStatus::Unknown(s)),
         }
     }
 }
@@ -84,7 +98,8 @@ impl<'de> Deserialize<'de> for FineTuneStatus {
 #[derive(Debug, Deserialize)]
 pub struct FineTuneResponse {
     pub id: String,
-    /* pub object: "fine-tune", */
+    #[serde(default)]
+    pub object: String,
     pub model: String,
     pub created_at: u64,
     pub events: Vec<FineTuneEvent>,
@@ -96,82 +111,72 @@ pub struct FineTuneResponse {
     pub validation_files: Vec<FileInfo>,
     pub training_files: Vec<FileInfo>,
     pub updated_at: u64,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct FineTuneDeleteResponse {
     pub id: String,
-    /* pub object: "fine-tune", */
+    #[serde(default)]
+    pub object: String,
     pub deleted: bool,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Context {
-    pub async fn create_fine_tune(&self, request: CreateFineTuneRequest) -> anyhow::Result<FineTuneResponse> {
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/fine-tunes")))
-                .json(&request)
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<FineTuneResponse>()
-                .await?
-        )
+    pub async fn create_fine_tune(&self, request: CreateFineTuneRequest) -> Result<FineTuneResponse> {
+        Ok(self.create_fine_tune_with_metadata(request).await?.data)
     }
 
-    pub async fn get_fine_tune(&self, id: impl Into<String>) -> anyhow::Result<FineTuneResponse> {
-        Ok(
-            self.with_auth(Client::builder().build()?.get(&format!("{API_URL}/v1/fine-tunes/{}", id.into())))
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<FineTuneResponse>()
-                .await?
-        )
+    /// Like [`create_fine_tune`](Context::create_fine_tune), along with the metadata of the response
+    pub async fn create_fine_tune_with_metadata(&self, request: CreateFineTuneRequest) -> Result<WithMetadata<FineTuneResponse>> {
+        self.execute_json_with_metadata::<FineTuneResponse>(ApiRequest::post("fine-tunes").json(&request)?).await
+    }
+
+    pub async fn get_fine_tune(&self, id: impl Into<String>) -> Result<FineTuneResponse> {
+        Ok(self.get_fine_tune_with_metadata(id).await?.data)
+    }
+
+    /// Like [`get_fine_tune`](Context::get_fine_tune), along with the metadata of the response
+    pub async fn get_fine_tune_with_metadata(&self, id: impl Into<String>) -> Result<WithMetadata<FineTuneResponse>> {
+        self.execute_json_with_metadata::<FineTuneResponse>(ApiRequest::get(format!("fine-tunes/{}", id.into()))).await
     }
     
-    pub async fn list_fine_tunes(&self) -> anyhow::Result<Vec<FineTuneResponse>> {
-        Ok(
-            self.with_auth(Client::builder().build()?.get(&format!("{API_URL}/v1/fine-tunes")))
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<DataList<FineTuneResponse>>()
-                .await?
-                .data
-        )
+    pub async fn list_fine_tunes(&self) -> Result<Vec<FineTuneResponse>> {
+        Ok(self.list_fine_tunes_with_metadata().await?.data)
+    }
+
+    /// Like [`list_fine_tunes`](Context::list_fine_tunes), along with the metadata of the response
+    pub async fn list_fine_tunes_with_metadata(&self) -> Result<WithMetadata<Vec<FineTuneResponse>>> {
+        Ok(self.execute_json_with_metadata::<DataList<FineTuneResponse>>(ApiRequest::get("fine-tunes")).await?.map(|list| list.data))
     }
 
-    pub async fn cancel_fine_tune(&self, id: impl Into<String>) -> anyhow::Result<FineTuneResponse> {
-        Ok(
-            self.with_auth(Client::builder().build()?.delete(&format!("{API_URL}/v1/fine-tunes/{}", id.into())))
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<FineTuneResponse>()
-                .await?
-        )
+    pub async fn cancel_fine_tune(&self, id: impl Into<String>) -> Result<FineTuneResponse> {
+        Ok(self.cancel_fine_tune_with_metadata(id).await?.data)
+    }
+
+    /// Like [`cancel_fine_tune`](Context::cancel_fine_tune), along with the metadata of the response
+    pub async fn cancel_fine_tune_with_metadata(&self, id: impl Into<String>) -> Result<WithMetadata<FineTuneResponse>> {
+        self.execute_json_with_metadata::<FineTuneResponse>(ApiRequest::delete(format!("fine-tunes/{}", id.into()))).await
     }
     
-    pub async fn list_fine_tune_events(&self, id: impl Into<String>) -> anyhow::Result<Vec<FineTuneEvent>> {
-        Ok(
-            self.with_auth(Client::builder().build()?.get(&format!("{API_URL}/v1/fine-tunes/{}/events", id.into())))
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<DataList<FineTuneEvent>>()
-                .await?
-                .data
-        )
+    pub async fn list_fine_tune_events(&self, id: impl Into<String>) -> Result<Vec<FineTuneEvent>> {
+        Ok(self.list_fine_tune_events_with_metadata(id).await?.data)
+    }
+
+    /// Like [`list_fine_tune_events`](Context::list_fine_tune_events), along with the metadata of the response
+    pub async fn list_fine_tune_events_with_metadata(&self, id: impl Into<String>) -> Result<WithMetadata<Vec<FineTuneEvent>>> {
+        Ok(self.execute_json_with_metadata::<DataList<FineTuneEvent>>(ApiRequest::get(format!("fine-tunes/{}/events", id.into()))).await?.map(|list| list.data))
+    }
+
+    pub async fn delete_fine_tune(&self, id: impl Into<String>) -> Result<FineTuneDeleteResponse> {
+        Ok(self.delete_fine_tune_with_metadata(id).await?.data)
     }
 
-    pub async fn delete_fine_tune(&self, id: impl Into<String>) -> anyhow::Result<FineTuneDeleteResponse> {
-        Ok(
-            self.with_auth(Client::builder().build()?.delete(&format!("{API_URL}/v1/fine-tunes/{}", id.into())))
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<FineTuneDeleteResponse>()
-                .await?
-        )
+    /// Like [`delete_fine_tune`](Context::delete_fine_tune), along with the metadata of the response
+    pub async fn delete_fine_tune_with_metadata(&self, id: impl Into<String>) -> Result<WithMetadata<FineTuneDeleteResponse>> {
+        self.execute_json_with_metadata::<FineTuneDeleteResponse>(ApiRequest::delete(format!("fine-tunes/{}", id.into()))).await
     }
 }
\ No newline at end of file
@@ -1,26 +1,30 @@
+use std::fmt::Display;
+
 use derive_builder::Builder;
-use reqwest::Client;
 use serde::{Serialize, Deserialize};
 
-use crate::context::{API_URL, Context};
+use crate::{metadata::WithMetadata, context::Context, error::Result, request::ApiRequest, util::ExtraFields};
 
 #[derive(Debug, Clone)]
 pub enum ResponseFormat {
     URL,
     Base64,
+    /// A format this version of the crate does not know about
+    Unknown(String),
 }
 
-impl ToString for ResponseFormat {
-    fn to_string(&self) -> String {
+impl Display for ResponseFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::URL => "url".to_string(),
-            Self::Base64 => "b64_json".to_string(),
+            Self::URL => f.write_str("url"),
+            Self::Base64 => f.write_str("b64_json"),
+            Self::Unknown(format) => f.write_str(format),
         }
     }
 }
 
 impl Serialize for ResponseFormat {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer {
         serializer.serialize_str(&self.to_string())
@@ -32,20 +36,34 @@ pub enum ImageSize {
     Size256,
     Size512,
     Size1024,
+    Size1792x1024,
+    Size1024x1792,
+    Size1536x1024,
+    Size1024x1536,
+    /// Let the model choose
+    Auto,
+    /// A size this version of the crate does not know about, e.g. `2048x2048`
+    Unknown(String),
 }
 
-impl ToString for ImageSize {
-    fn to_string(&self) -> String {
+impl Display for ImageSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Size256 => "256x256".to_string(),
-            Self::Size512 => "512x512".to_string(),
-            Self::Size1024 => "1024x1024".to_string(),
+            Self::Size256 => f.write_str("256x256"),
+            Self::Size512 => f.write_str("512x512"),
+            Self::Size1024 => f.write_str("1024x1024"),
+            Self::Size1792x1024 => f.write_str("1792x1024"),
+            Self::Size1024x1792 => f.write_str("1024x1792"),
+            Self::Size1536x1024 => f.write_str("1536x1024"),
+            Self::Size1024x1536 => f.write_str("1024x1536"),
+            Self::Auto => f.write_str("auto"),
+            Self::Unknown(size) => f.write_str(size),
         }
     }
 }
 
 impl Serialize for ImageSize {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer {
         serializer.serialize_str(&self.to_string())
@@ -58,6 +76,9 @@ pub struct ImageRequest {
     pub prompt: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(setter(into, strip_option), default)]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[builder(setter(into, strip_option), default)]
     pub n: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[builder(setter(into, strip_option), default)]
@@ -73,27 +94,34 @@ pub struct ImageRequest {
     pub temperature: Option<f64>,
 }
 
-#[derive(Debug, Deserialize)]
-struct RawImage {
-    pub(crate) url: Option<String>,
-    pub(crate) b64_json: Option<String>,
+/// A generated image. Which of `url` and `b64_json` is set depends on the requested [`ResponseFormat`]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Image {
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub b64_json: Option<String>,
+    /// The prompt the image was actually generated from, if the model rewrote it
+    #[serde(default)]
+    pub revised_prompt: Option<String>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
-#[derive(Debug)]
-pub enum Image {
-    URL(String),
-    Base64(String),
+/// The data of an [`Image`], as a URL or Base64-encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageSource<'a> {
+    URL(&'a str),
+    Base64(&'a str),
 }
 
-impl<'de> Deserialize<'de> for Image {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de> {
-        let raw = RawImage::deserialize(deserializer)?;
-        match (raw.url, raw.b64_json) {
-            (Some(url), None) => Ok(Self::URL(url)),
-            (None, Some(b64)) => Ok(Self::Base64(b64)),
-            _ => Err(serde::de::Error::custom("Invalid image")),
+impl Image {
+    /// The URL of the image, or its Base64-encoded data if there is no URL
+    pub fn source(&self) -> Option<ImageSource<'_>> {
+        match (self.url.as_deref(), self.b64_json.as_deref()) {
+            (Some(url), _) => Some(ImageSource::URL(url)),
+            (None, Some(b64)) => Some(ImageSource::Base64(b64)),
+            (None, None) => None,
         }
     }
 }
@@ -102,18 +130,17 @@ impl<'de> Deserialize<'de> for Image {
 pub struct ImageResponse {
     pub created: u64,
     pub data: Vec<Image>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Context {
-    pub async fn create_image(&self, image_request: ImageRequest) -> anyhow::Result<ImageResponse> {
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/images/generations")))
-                .json(&image_request)
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<ImageResponse>()
-                .await?
-        )
+    pub async fn create_image(&self, image_request: ImageRequest) -> Result<ImageResponse> {
+        Ok(self.create_image_with_metadata(image_request).await?.data)
+    }
+
+    /// Like [`create_image`](Context::create_image), along with the metadata of the response
+    pub async fn create_image_with_metadata(&self, image_request: ImageRequest) -> Result<WithMetadata<ImageResponse>> {
+        self.execute_json_with_metadata::<ImageResponse>(ApiRequest::post("images/generations").json(&image_request)?).await
     }
 }
\ No newline at end of file
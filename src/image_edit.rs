@@ -1,6 +1,5 @@
 use derive_builder::Builder;
-use reqwest::{multipart::Form, Client};
-use crate::{image::{ResponseFormat, ImageResponse, ImageSize}, context::{API_URL, Context}, util::FileResource};
+use crate::{metadata::WithMetadata, image::{ResponseFormat, ImageResponse, ImageSize}, context::Context, error::Result, request::ApiRequest, util::{FileResource, MultipartForm}};
 
 #[derive(Debug, Builder)]
 #[builder(pattern = "owned")]
@@ -12,6 +11,8 @@ pub struct ImageEditRequest {
     #[builder(setter(into))]
     pub prompt: String,
     #[builder(setter(into, strip_option), default)]
+    pub model: Option<String>,
+    #[builder(setter(into, strip_option), default)]
     pub n: Option<u32>,
     #[builder(setter(into, strip_option), default)]
     pub response_format: Option<ResponseFormat>,
@@ -24,11 +25,19 @@ pub struct ImageEditRequest {
 }
 
 impl Context {
-    pub async fn create_image_edit(&self, req: ImageEditRequest) -> anyhow::Result<ImageResponse> {
-        let mut form = Form::new();
+    pub async fn create_image_edit(&self, req: ImageEditRequest) -> Result<ImageResponse> {
+        Ok(self.create_image_edit_with_metadata(req).await?.data)
+    }
+
+    /// Like [`create_image_edit`](Context::create_image_edit), along with the metadata of the response
+    pub async fn create_image_edit_with_metadata(&self, req: ImageEditRequest) -> Result<WithMetadata<ImageResponse>> {
+        let mut form = MultipartForm::new();
         form = form.text("prompt", req.prompt);
-        form = req.image.write_file(form, "image");
+        form = req.image.write_file(form, "image").await?;
 
+        if let Some(model) = req.model {
+            form = form.text("model", model);
+        }
         if let Some(n) = req.n {
             form = form.text("n", n.to_string());
         }
@@ -40,7 +49,7 @@ impl Context {
         }
         
         if let Some(mask) = req.mask {
-            form = mask.write_file(form, "mask");
+            form = mask.write_file(form, "mask").await?;
         }
 
         if let Some(temperature) = req.temperature {
@@ -51,14 +60,6 @@ impl Context {
             form = form.text("size", size.to_string());
         }
         
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/images/edits")))
-                .multipart(form)
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<ImageResponse>()
-                .await?
-        )
+        self.execute_json_with_metadata::<ImageResponse>(ApiRequest::post("images/edits").multipart(form)).await
     }
 }
\ No newline at end of file
@@ -1,7 +1,6 @@
 use derive_builder::Builder;
-use reqwest::{multipart::Form, Client};
 
-use crate::{image::{ImageSize, ResponseFormat, ImageResponse}, context::{API_URL, Context}, util::FileResource};
+use crate::{metadata::WithMetadata, image::{ImageSize, ResponseFormat, ImageResponse}, context::Context, error::Result, request::ApiRequest, util::{FileResource, MultipartForm}};
 
 #[derive(Debug, Builder)]
 #[builder(pattern = "owned")]
@@ -9,6 +8,8 @@ pub struct ImageVariationRequest {
     #[builder(setter(into))]
     pub image: FileResource,
     #[builder(setter(into, strip_option), default)]
+    pub model: Option<String>,
+    #[builder(setter(into, strip_option), default)]
     pub n: Option<u32>,
     #[builder(setter(into, strip_option), default)]
     pub size: Option<ImageSize>,
@@ -20,10 +21,18 @@ pub struct ImageVariationRequest {
 
 
 impl Context {
-    pub async fn create_image_variation(&self, req: ImageVariationRequest) -> anyhow::Result<ImageResponse> {
-        let mut form = Form::new();
-        form = req.image.write_file(form, "image");
+    pub async fn create_image_variation(&self, req: ImageVariationRequest) -> Result<ImageResponse> {
+        Ok(self.create_image_variation_with_metadata(req).await?.data)
+    }
 
+    /// Like [`create_image_variation`](Context::create_image_variation), along with the metadata of the response
+    pub async fn create_image_variation_with_metadata(&self, req: ImageVariationRequest) -> Result<WithMetadata<ImageResponse>> {
+        let mut form = MultipartForm::new();
+        form = req.image.write_file(form, "image").await?;
+
+        if let Some(model) = req.model {
+            form = form.text("model", model);
+        }
         if let Some(n) = req.n {
             form = form.text("n", n.to_string());
         }
@@ -38,14 +47,6 @@ impl Context {
             form = form.text("size", size.to_string());
         }
         
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/images/variations")))
-                .multipart(form)
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<ImageResponse>()
-                .await?
-        )
+        self.execute_json_with_metadata::<ImageResponse>(ApiRequest::post("images/variations").multipart(form)).await
     }
 }
\ No newline at end of file
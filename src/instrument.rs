@@ -0,0 +1,127 @@
+//! Spans and events emitted with the `tracing` feature. Without it, [`RequestTrace`] does nothing
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use std::{future::Future, time::{Duration, Instant}};
+
+    use reqwest::{header::HeaderMap, StatusCode};
+    use tracing::{field::Empty, Instrument, Span};
+
+    use crate::{error::Error, rate_limit, request::ApiRequest};
+
+    /// Span covering an API call, from its first attempt until its response has been read
+    #[derive(Debug, Clone)]
+    pub(crate) struct RequestTrace {
+        span: Span,
+        started: Instant,
+    }
+
+    impl RequestTrace {
+        pub(crate) fn new(request: &ApiRequest) -> Self {
+            let span = tracing::info_span!(
+                "openai.request",
+                endpoint = %request.path,
+                model = Empty,
+                request_id = Empty,
+                status = Empty,
+                retries = Empty,
+                duration_ms = Empty,
+                prompt_tokens = Empty,
+                completion_tokens = Empty,
+                total_tokens = Empty,
+                chunks = Empty,
+                error = Empty,
+            );
+            if let Some(model) = rate_limit::request_model(request) {
+                span.record("model", model);
+            }
+            Self { span, started: Instant::now() }
+        }
+
+        pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+            future.instrument(self.span.clone())
+        }
+
+        pub(crate) fn response(&self, status: StatusCode, headers: &HeaderMap) {
+            self.span.record("status", status.as_u16());
+            if let Some(request_id) = headers.get("x-request-id").and_then(|value| value.to_str().ok()) {
+                self.span.record("request_id", request_id);
+            }
+        }
+
+        pub(crate) fn retry(&self, attempt: u32, error: &Error, delay: Duration) {
+            tracing::debug!(parent: &self.span, attempt, error = %error, delay_ms = delay.as_millis() as u64, "retrying request");
+        }
+
+        pub(crate) fn attempts(&self, retries: u32) {
+            self.span.record("retries", retries);
+        }
+
+        pub(crate) fn failed(&self, error: &Error) {
+            self.span.record("error", tracing::field::display(error));
+            self.finished();
+        }
+
+        /// Record the duration so far. Called again by streams once they end
+        pub(crate) fn finished(&self) {
+            self.span.record("duration_ms", self.started.elapsed().as_millis() as u64);
+        }
+
+        /// Record the token usage of a response body or streamed chunk, if it has any
+        pub(crate) fn usage(&self, body: &[u8]) {
+            if let Some(tokens) = rate_limit::reported_usage(body) {
+                for (field, value) in [("prompt_tokens", tokens.prompt_tokens), ("completion_tokens", tokens.completion_tokens), ("total_tokens", tokens.total_tokens)] {
+                    if let Some(value) = value {
+                        self.span.record(field, value);
+                    }
+                }
+            }
+        }
+
+        pub(crate) fn chunk(&self, index: usize, data: &str) {
+            self.span.record("chunks", index as u64 + 1);
+            tracing::trace!(parent: &self.span, chunk = index, bytes = data.len(), "received chunk");
+            if data.contains("\"usage\"") {
+                self.usage(data.as_bytes());
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use std::{future::Future, time::Duration};
+
+    use reqwest::{header::HeaderMap, StatusCode};
+
+    use crate::{error::Error, request::ApiRequest};
+
+    #[derive(Debug, Clone)]
+    pub(crate) struct RequestTrace;
+
+    impl RequestTrace {
+        pub(crate) fn new(_request: &ApiRequest) -> Self {
+            Self
+        }
+
+        pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+            future
+        }
+
+        pub(crate) fn response(&self, _status: StatusCode, _headers: &HeaderMap) {}
+
+        pub(crate) fn retry(&self, _attempt: u32, _error: &Error, _delay: Duration) {}
+
+        pub(crate) fn attempts(&self, _retries: u32) {}
+
+        pub(crate) fn failed(&self, _error: &Error) {}
+
+        pub(crate) fn finished(&self) {}
+
+        pub(crate) fn usage(&self, _body: &[u8]) {}
+
+        pub(crate) fn chunk(&self, _index: usize, _data: &str) {}
+    }
+}
+
+pub(crate) use imp::RequestTrace;
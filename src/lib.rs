@@ -1,7 +1,24 @@
 pub mod context;
+pub mod config;
+pub mod auth;
+pub mod azure;
+pub mod error;
+pub mod retry;
+pub mod rate_limit;
+pub mod middleware;
+pub mod metadata;
 pub mod model;
 pub mod completion;
 pub mod chat;
+pub mod tools;
+#[cfg(feature = "tokenizer")]
+pub mod context_window;
+pub mod conversation;
+pub mod transcript;
+pub mod accumulator;
+#[cfg(feature = "tokenizer")]
+pub mod tokenizer;
+pub mod logprobs;
 pub mod edits;
 pub mod image;
 pub mod image_edit;
@@ -14,16 +31,26 @@ pub mod fine_tune;
 pub mod moderation;
 
 pub mod util;
+mod instrument;
+mod request;
+mod stream;
 
 #[cfg(test)]
 mod tests {
+    use std::{sync::Arc, time::Duration};
+
     use futures::StreamExt;
     use tokio::fs::File;
 
-    use crate::chat::{ChatHistoryBuilder, ChatMessage, Role};
+    use crate::chat::{Content, ContentPart, ImageDetail, InputAudioFormat, ChatHistoryBuilder, ChatMessage, Role, FunctionDefinition, FunctionCallMode, FinishReason, ChatCompletionDeltaResponse, Tool, ToolChoice, DeltaToolCall, StreamOptions};
+    use crate::accumulator::{ChatCompletionAccumulator, ChatCompletionStreamExt};
+    #[cfg(feature = "tokenizer")]
+    use crate::tokenizer::{Encoding, Tokenizer, count_message_tokens, count_prompt_tokens};
     use crate::context::Context;
+    use crate::azure::AzureConfigBuilder;
+    use crate::auth::{AzureAdToken, KeyPool, KeySelection, RotatingKey};
     use crate::completion::CompletionRequestBuilder;
-    use crate::image::{Image, ResponseFormat, ImageRequestBuilder};
+    use crate::image::{ImageResponse, ImageSource, ResponseFormat, ImageRequestBuilder};
     use crate::edits::EditRequestBuilder;
     use crate::image_edit::ImageEditRequestBuilder;
     use crate::image_variation::ImageVariationRequestBuilder;
@@ -31,9 +58,2223 @@ mod tests {
     use crate::transcription::{TranscriptionRequestBuilder, AudioFile};
     use crate::translation::TranslationRequestBuilder;
     use crate::moderation::ModerationRequestBuilder;
+    use crate::context::ContextBuilder;
+    use crate::config::ContextConfig;
+    use crate::middleware::{Middleware, OutgoingRequest, ReceivedResponse};
+    #[cfg(feature = "tokenizer")]
+    use crate::context_window::{ContextWindowManagerBuilder, ContextWindowStrategy, context_window};
+    use crate::conversation::Conversation;
+    use crate::transcript::{ChatExample, from_jsonl, to_jsonl};
+    use crate::tools::{ToolRegistry, ToolLoopOptions, ToolLoopOptionsBuilder, ToolLoopStop};
+    use crate::error::Error;
+    use crate::fine_tune::FineTuneStatus;
+    use crate::retry::{RetryPolicy, RetryPolicyBuilder};
+    use crate::rate_limit::{RateLimiterBuilder, RateLimits};
+
+    use reqwest::header::{HeaderName, HeaderValue};
+    use wiremock::{MockServer, Mock, ResponseTemplate};
+    use wiremock::matchers::{method, path, header, body_partial_json, query_param};
 
     fn get_api() -> anyhow::Result<Context> {
-        Ok(Context::new(std::fs::read_to_string(std::path::Path::new("apikey.txt"))?.trim().to_string()))
+        match Context::from_env() {
+            Ok(ctx) => Ok(ctx),
+            Err(_) => Ok(Context::new(std::fs::read_to_string(std::path::Path::new("apikey.txt"))?.trim().to_string())),
+        }
+    }
+
+    fn get_mock_api(server: &MockServer) -> Context {
+        ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .build()
+            .unwrap()
+    }
+
+    fn mock_chat_response() -> serde_json::Value {
+        serde_json::json!({
+            "id": "chatcmpl-123",
+            "object": "chat.completion",
+            "created": 1677652288,
+            "model": "gpt-3.5-turbo",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "this is a test" },
+                "finish_reason": "stop"
+            }],
+            "usage": { "prompt_tokens": 9, "completion_tokens": 4, "total_tokens": 13 }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_mock_base_url() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("authorization", "Bearer test-key"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let completion = get_mock_api(&server).create_chat_completion_sync(
+            ChatHistoryBuilder::default()
+                .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+                .model("gpt-3.5-turbo")
+        ).await;
+
+        assert!(completion.is_ok(), "Could not create completion: {}", completion.unwrap_err());
+        assert_eq!(completion.unwrap().choices[0].message.text(), Some("this is a test"));
+    }
+
+    #[tokio::test]
+    async fn test_mock_path_prefix() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/openai/v2/models"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "object": "list",
+                "data": [{
+                    "id": "gpt-3.5-turbo",
+                    "object": "model",
+                    "created": 1677610602,
+                    "owned_by": "openai",
+                    "permission": [],
+                    "root": "gpt-3.5-turbo",
+                    "parent": null
+                }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(format!("{}/", server.uri()))
+            .path_prefix("openai/v2/")
+            .build()
+            .unwrap();
+
+        let models = ctx.get_models().await;
+        assert!(models.is_ok(), "Could not get models: {}", models.unwrap_err());
+        assert_eq!(models.unwrap()[0].id, "gpt-3.5-turbo");
+    }
+
+    fn mock_chat_stream_body() -> String {
+        [
+            r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-3.5-turbo","choices":[{"index":0,"delta":{"role":"assistant"},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-3.5-turbo","choices":[{"index":0,"delta":{"content":"this is"},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-3.5-turbo","choices":[{"index":0,"delta":{"content":" a test"},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-3.5-turbo","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+            "[DONE]",
+        ].iter().map(|data| format!("data: {data}\n\n")).collect()
+    }
+
+    #[tokio::test]
+    async fn test_mock_api_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "error": {
+                    "message": "This model's maximum context length is 4097 tokens.",
+                    "type": "invalid_request_error",
+                    "param": "messages",
+                    "code": "context_length_exceeded"
+                }
+            })))
+            .mount(&server)
+            .await;
+
+        let completion = get_mock_api(&server).create_chat_completion_sync(
+            ChatHistoryBuilder::default()
+                .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+                .model("gpt-3.5-turbo")
+        ).await;
+
+        let error = completion.unwrap_err();
+        assert!(error.is_context_length_exceeded(), "Unexpected error: {error}");
+        assert_eq!(error.status(), Some(reqwest::StatusCode::BAD_REQUEST));
+        let api_error = error.api_error().unwrap();
+        assert_eq!(api_error.error_type.as_deref(), Some("invalid_request_error"));
+        assert_eq!(api_error.param.as_deref(), Some("messages"));
+
+        // Builder validation errors are surfaced before anything is sent
+        let completion = get_mock_api(&server).create_chat_completion_sync(ChatHistoryBuilder::default().model("gpt-3.5-turbo")).await;
+        assert!(matches!(completion, Err(Error::Builder(_))));
+    }
+
+    #[tokio::test]
+    async fn test_mock_chat_stream() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(mock_chat_stream_body(), "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        let stream = get_mock_api(&server).create_chat_completion_streamed(
+            ChatHistoryBuilder::default()
+                .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+                .model("gpt-3.5-turbo")
+        ).await;
+        assert!(stream.is_ok(), "Could not create completion: {}", stream.err().unwrap());
+
+        let chunks = stream.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(chunks.len(), 4);
+        let content = chunks.iter()
+            .filter_map(|chunk| chunk.as_ref().unwrap().choices[0].delta.content.clone())
+            .collect::<String>();
+        assert_eq!(content, "this is a test");
+    }
+
+    #[tokio::test]
+    async fn test_mock_completion_stream() {
+        let server = MockServer::start().await;
+        let body = [
+            r#"{"id":"cmpl-123","object":"text_completion","created":1677652288,"model":"gpt-3.5-turbo-instruct","choices":[{"text":"this is","index":0,"logprobs":null,"finish_reason":null}]}"#,
+            r#"{"id":"cmpl-123","object":"text_completion","created":1677652288,"model":"gpt-3.5-turbo-instruct","choices":[{"text":" a test","index":0,"logprobs":null,"finish_reason":"stop"}]}"#,
+            r#"{"error":{"message":"The server had an error while processing your request","type":"server_error","param":null,"code":null}}"#,
+            "[DONE]",
+        ].iter().map(|data| format!("data: {data}\n\n")).collect::<String>();
+        Mock::given(method("POST"))
+            .and(path("/v1/completions"))
+            .and(body_partial_json(serde_json::json!({ "stream": true })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        let stream = get_mock_api(&server).create_completion_streamed(
+            CompletionRequestBuilder::default()
+                .model("gpt-3.5-turbo-instruct")
+                .prompt("Say 'this is a test'")
+                .build()
+                .unwrap()
+        ).await;
+        assert!(stream.is_ok(), "Could not create completion: {}", stream.err().unwrap());
+
+        let chunks = stream.unwrap().collect::<Vec<_>>().await;
+        assert_eq!(chunks.len(), 3);
+        let text = chunks[..2].iter()
+            .map(|chunk| chunk.as_ref().unwrap().choices[0].text.clone())
+            .collect::<String>();
+        assert_eq!(text, "this is a test");
+        assert_eq!(chunks[1].as_ref().unwrap().choices[0].finish_reason.as_deref(), Some("stop"));
+        assert!(matches!(chunks[2], Err(Error::Api { error: Some(ref error), .. }) if error.error_type.as_deref() == Some("server_error")));
+    }
+
+    #[cfg(feature = "tokenizer")]
+    #[test]
+    fn test_tokenizer_known_vectors() {
+        let cl100k = Encoding::Cl100kBase.tokenizer();
+        assert_eq!(cl100k.encode("hello world"), vec![15339, 1917]);
+        assert_eq!(cl100k.encode("tiktoken is great!"), vec![83, 1609, 5963, 374, 2294, 0]);
+        assert_eq!(Encoding::O200kBase.tokenizer().encode("hello world"), vec![24912, 2375]);
+        assert_eq!(Encoding::P50kBase.tokenizer().encode("hello world"), vec![31373, 995]);
+
+        assert_eq!(cl100k.encode_with_special_tokens("hi<|endoftext|>"), vec![6151, 100257]);
+        assert_eq!(cl100k.encode("<|endoftext|>").len(), 7);
+
+        let text = "Räksmörgås 🦐 costs 123456 kr.\n\n  Indented";
+        assert_eq!(cl100k.encode(text), vec![49, 2357, 2857, 76, 3029, 2026, 3870, 82, 11410, 99, 238, 7194, 220, 4513, 10961, 23975, 382, 220, 2314, 16243]);
+        assert_eq!(cl100k.decode(&cl100k.encode(text)), text);
+        assert_eq!(Encoding::O200kBase.tokenizer().decode(&Encoding::O200kBase.tokenizer().encode(text)), text);
+
+        assert_eq!(Encoding::for_model("gpt-4o-mini"), Some(Encoding::O200kBase));
+        assert_eq!(Encoding::for_model("ft:gpt-3.5-turbo-0613:org::abc123"), Some(Encoding::Cl100kBase));
+        assert_eq!(Encoding::for_model("text-davinci-003"), Some(Encoding::P50kBase));
+        assert!(Tokenizer::for_model("whisper-1").is_none());
+
+        let request = CompletionRequestBuilder::default()
+            .model("text-davinci-003")
+            .prompt(vec!["hello world", "hello"])
+            .build()
+            .unwrap();
+        assert_eq!(count_prompt_tokens(&request), Some(3));
+    }
+
+    #[cfg(feature = "tokenizer")]
+    #[test]
+    fn test_tokenizer_backtrack_limit() {
+        use base64::{prelude::BASE64_STANDARD, Engine};
+
+        // Only single bytes, and a pattern that backtracks exponentially on runs of `a` not followed by `b`
+        let ranks = (0..=255u8).map(|byte| format!("{} {byte}\n", BASE64_STANDARD.encode([byte]))).collect::<String>();
+        let tokenizer = Tokenizer::new(&ranks, &[], r"(?:a|aa)+(?=b)|\s+|\S");
+
+        let text = format!("{} {}ab", "a".repeat(40), "a".repeat(300));
+        let tokens = tokenizer.encode(&text);
+        assert_eq!(tokens.len(), text.len());
+        assert_eq!(tokenizer.decode(&tokens), text);
+    }
+
+    #[cfg(feature = "tokenizer")]
+    #[test]
+    fn test_count_message_tokens() {
+        let example = |name: &str| Some(name.to_string());
+        let messages = vec![
+            ChatMessage::new(Role::System, "You are a helpful, pattern-following assistant that translates corporate jargon into plain English.", None),
+            ChatMessage::new(Role::System, "New synergies will help drive top-line growth.", example("example_user")),
+            ChatMessage::new(Role::System, "Things working well together will increase revenue.", example("example_assistant")),
+            ChatMessage::new(Role::System, "Let's circle back when we have more bandwidth to touch base on opportunities for increased leverage.", example("example_user")),
+            ChatMessage::new(Role::System, "Let's talk later when we're less busy about how to do better.", example("example_assistant")),
+            ChatMessage::new(Role::User, "This late pivot means we don't have time to boil the ocean for the client project.", None),
+        ];
+
+        // Counts produced by tiktoken for these messages
+        assert_eq!(count_message_tokens("gpt-4", &messages), Some(128));
+        assert_eq!(count_message_tokens("gpt-4o", &messages), Some(123));
+        assert_eq!(count_message_tokens("whisper-1", &messages), None);
+    }
+
+    fn long_chat() -> Vec<ChatMessage> {
+        let filler = "lorem ipsum ".repeat(75);
+        vec![
+            ChatMessage::new(Role::System, "You are a support bot.", None),
+            ChatMessage::new(Role::User, filler.clone(), None),
+            ChatMessage {
+                content: None,
+                tool_calls: Some(mock_tool_calls_response()["choices"][0]["message"]["tool_calls"].as_array().unwrap()[..1].iter()
+                    .map(|call| serde_json::from_value(call.clone()).unwrap())
+                    .collect()),
+                ..ChatMessage::new(Role::Assistant, "", None)
+            },
+            ChatMessage::tool_result("call_1", filler.clone()),
+            ChatMessage::new(Role::User, "My order number is 1234.", None),
+            ChatMessage::new(Role::Assistant, filler, None),
+            ChatMessage::new(Role::User, "Where is my order?", None),
+        ]
+    }
+
+    #[cfg(feature = "tokenizer")]
+    fn message_contents(request: ChatHistoryBuilder) -> Vec<Option<String>> {
+        request.build().unwrap().messages.iter().map(|message| message.text().map(String::from)).collect()
+    }
+
+    #[cfg(feature = "tokenizer")]
+    #[tokio::test]
+    async fn test_fit_context_window() {
+        let server = MockServer::start().await;
+        let ctx = get_mock_api(&server);
+        let messages = long_chat();
+        let request = ChatHistoryBuilder::default().model("gpt-4").messages(messages.clone());
+        let contents = messages.iter().map(|message| message.text().map(String::from)).collect::<Vec<_>>();
+
+        assert_eq!(context_window("gpt-4o-mini"), Some(128_000));
+        assert_eq!(context_window("gpt-4.5-preview"), Some(128_000));
+        assert_eq!(context_window("gpt-4-0613"), Some(8_192));
+
+        // Fits the model's window
+        let fitted = ctx.fit_context_window(request.clone(), &Default::default()).await.unwrap();
+        assert_eq!(message_contents(fitted), contents);
+
+        // The tool call is dropped together with its result
+        let manager = ContextWindowManagerBuilder::default()
+            .max_context_tokens(250)
+            .reserved_completion_tokens(0)
+            .pin(4)
+            .build()
+            .unwrap();
+        let fitted = ctx.fit_context_window(request.clone(), &manager).await.unwrap();
+        assert_eq!(message_contents(fitted), [&contents[0], &contents[4], &contents[5], &contents[6]].map(Clone::clone));
+
+        let manager = ContextWindowManagerBuilder::default()
+            .strategy(ContextWindowStrategy::KeepLastTurns(1))
+            .max_context_tokens(400)
+            .reserved_completion_tokens(0)
+            .pin(4)
+            .build()
+            .unwrap();
+        let fitted = ctx.fit_context_window(request, &manager).await.unwrap();
+        assert_eq!(message_contents(fitted), [&contents[0], &contents[4], &contents[6]].map(Clone::clone));
+    }
+
+    #[cfg(feature = "tokenizer")]
+    #[tokio::test]
+    async fn test_mock_fit_context_window_summarize() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "model": "gpt-4o-mini", "max_tokens": 20 })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let manager = ContextWindowManagerBuilder::default()
+            .strategy(ContextWindowStrategy::Summarize { model: "gpt-4o-mini".to_string(), max_summary_tokens: 20 })
+            .max_context_tokens(250)
+            .reserved_completion_tokens(0)
+            .pin(4)
+            .build()
+            .unwrap();
+        let fitted = get_mock_api(&server).fit_context_window(
+            ChatHistoryBuilder::default().model("gpt-4").messages(long_chat()),
+            &manager
+        ).await.unwrap().build().unwrap();
+
+        assert_eq!(fitted.messages.len(), 5);
+        assert!(matches!(fitted.messages[1].role, Role::System));
+        assert_eq!(fitted.messages[1].text(), Some("Summary of the earlier conversation: this is a test"));
+        assert_eq!(fitted.messages[2].text(), Some("My order number is 1234."));
+    }
+
+    /// Matches chat requests with exactly `count` messages
+    fn message_count(count: usize) -> impl Fn(&wiremock::Request) -> bool {
+        move |request: &wiremock::Request| request.body_json::<serde_json::Value>()
+            .map(|body| body["messages"].as_array().map(Vec::len) == Some(count))
+            .unwrap_or(false)
+    }
+
+    #[tokio::test]
+    async fn test_mock_conversation() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(message_count(4))
+            .and(body_partial_json(serde_json::json!({ "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Hello" },
+                { "role": "assistant", "content": "this is a test" },
+                { "role": "user", "content": "Again" },
+            ] })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(mock_chat_stream_body(), "text/event-stream"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(message_count(2))
+            .and(body_partial_json(serde_json::json!({ "messages": [{ "role": "system", "content": "Be brief." }, { "role": "user", "content": "Hello" }] })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "error": { "message": "Unexpected conversation", "type": "invalid_request_error", "param": null, "code": null }
+            })))
+            .mount(&server)
+            .await;
+
+        let mut conversation = Conversation::new(get_mock_api(&server), "gpt-3.5-turbo").system("Be brief.");
+        let response = conversation.send("Hello").await.unwrap();
+        assert_eq!(response.choices[0].message.text(), Some("this is a test"));
+        assert_eq!(conversation.messages().len(), 3);
+
+        let mut stream = conversation.send_streamed("Again").await.unwrap();
+        while let Some(chunk) = stream.next().await {
+            chunk.unwrap();
+        }
+        drop(stream);
+        assert_eq!(conversation.messages().len(), 5);
+        assert!(matches!(conversation.messages()[4].role, Role::Assistant));
+        assert_eq!(conversation.last_reply().unwrap().text(), Some("this is a test"));
+
+        // Failed requests leave the history untouched
+        assert!(conversation.send("Hello?").await.is_err());
+        assert_eq!(conversation.messages().len(), 5);
+
+        let fork = conversation.fork(3);
+        assert_eq!(fork.messages().len(), 3);
+
+        let undone = conversation.undo();
+        assert_eq!(undone.len(), 2);
+        assert_eq!(undone[0].text(), Some("Again"));
+        assert_eq!(conversation.messages().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_mock_stream_read_error() {
+        let server = MockServer::start().await;
+        let body = mock_chat_stream_body();
+        let first_chunk = &body[..body.find("\n\n").unwrap() + 2];
+        let mut corrupt = first_chunk.as_bytes().to_vec();
+        corrupt.extend_from_slice(b"data: \xff\xfe\n\n");
+        corrupt.extend_from_slice(&body.as_bytes()[first_chunk.len()..]);
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(corrupt, "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        let stream = get_mock_api(&server).create_chat_completion_streamed(
+            ChatHistoryBuilder::default()
+                .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+                .model("gpt-3.5-turbo")
+        ).await.unwrap();
+
+        // The read error ends the stream, without a second error for the missing `[DONE]`
+        let chunks = stream.collect::<Vec<_>>().await;
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].is_ok());
+        assert!(matches!(chunks[1], Err(Error::Stream(ref e)) if !e.contains("[DONE]")), "Unexpected chunk: {:?}", chunks[1]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_conversation_truncated_stream() {
+        let server = MockServer::start().await;
+        let body = mock_chat_stream_body();
+        let truncated = body[..body.find("data: [DONE]").unwrap()].to_string();
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(truncated, "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        let mut conversation = Conversation::new(get_mock_api(&server), "gpt-3.5-turbo").system("Be brief.");
+        let mut stream = conversation.send_streamed("Hello").await.unwrap();
+        let mut chunks = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            chunks.push(chunk);
+        }
+        drop(stream);
+
+        // Every chunk arrived, but without `[DONE]` the reply may be incomplete
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks[..4].iter().all(Result::is_ok));
+        assert!(matches!(chunks[4], Err(Error::Stream(_))));
+        assert_eq!(conversation.messages().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_chat_round_trip() {
+        let history = ChatHistoryBuilder::default()
+            .model("gpt-4o")
+            .messages(long_chat())
+            .stop(vec!["\n\n", "END"])
+            .logit_bias([(1234u64, -100i8)].into_iter().collect::<std::collections::HashMap<_, _>>())
+            .tools(vec![weather_tool()])
+            .tool_choice(ToolChoice::Function("get_weather".to_string()))
+            .function_call(FunctionCallMode::Force("get_weather".to_string()))
+            .stream(false)
+            .build()
+            .unwrap();
+        let json = serde_json::to_value(&history).unwrap();
+        let restored = serde_json::from_value::<crate::chat::ChatHistory>(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&restored).unwrap(), json);
+
+        let response = serde_json::from_value::<crate::chat::ChatCompletionSyncResponse>(mock_tool_calls_response()).unwrap();
+        let restored = serde_json::from_value::<crate::chat::ChatCompletionSyncResponse>(serde_json::to_value(&response).unwrap()).unwrap();
+        assert_eq!(restored.choices[0].finish_reason, Some(FinishReason::ToolCalls));
+        assert_eq!(restored.choices[0].message.tool_calls.as_ref().unwrap()[1].id, "call_2");
+
+        // Conversations resume with their settings and history
+        let path = std::env::temp_dir().join(format!("openai_rs_conversation_{}.json", std::process::id()));
+        let ctx = Context::new(String::from("test-key"));
+        let mut conversation = Conversation::with_settings(ctx.clone(), ChatHistoryBuilder::default().model("gpt-4o").temperature(0.5))
+            .system("Be brief.");
+        conversation.push(ChatMessage::new(Role::User, "Hello", None));
+        conversation.save_json(&path).await.unwrap();
+        let loaded = Conversation::load_json(ctx, &path).await.unwrap();
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(loaded.messages().len(), 2);
+        assert_eq!(loaded.to_history().unwrap().temperature, Some(0.5));
+
+        // Exported conversations keep the tools their tool calls refer to
+        let mut with_tools = Conversation::with_settings(
+            Context::new(String::from("test-key")),
+            ChatHistoryBuilder::default().model("gpt-4o").tools(vec![weather_tool()]).parallel_tool_calls(false),
+        );
+        with_tools.push(ChatMessage::new(Role::User, "Weather in Paris?", None));
+        let example = ChatExample::from(&with_tools);
+        assert_eq!(example.tools.as_ref().map(Vec::len), Some(1));
+        assert_eq!(example.parallel_tool_calls, Some(false));
+        assert_eq!(example.messages.len(), 1);
+
+        let examples = vec![ChatExample::from(history), ChatExample::from(&loaded)];
+        let jsonl = to_jsonl(&examples).unwrap();
+        assert_eq!(jsonl.lines().count(), 2);
+        assert!(jsonl.starts_with(r#"{"messages":[{"role":"system","content":"You are a support bot."}"#));
+        let restored = from_jsonl(&format!("{jsonl}\n")).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[0].messages.len(), 7);
+        assert!(restored[0].tools.is_some());
+        assert!(restored[1].tools.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_multimodal_message() {
+        let message = ChatMessage::with_parts(Role::User, vec![
+            ContentPart::text("What is in these images?"),
+            ContentPart::image_url("https://example.com/cat.png", Some(ImageDetail::Low)),
+            ContentPart::image_file("image/png", vec![0x89, b'P', b'N', b'G'], None).await.unwrap(),
+            ContentPart::input_audio(b"RIFF", InputAudioFormat::Wav),
+        ], None);
+
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "role": "user",
+            "content": [
+                { "type": "text", "text": "What is in these images?" },
+                { "type": "image_url", "image_url": { "url": "https://example.com/cat.png", "detail": "low" } },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,iVBORw==" } },
+                { "type": "input_audio", "input_audio": { "data": "UklGRg==", "format": "wav" } },
+            ]
+        }));
+
+        let restored = serde_json::from_value::<ChatMessage>(json).unwrap();
+        assert!(matches!(restored.content, Some(Content::Parts(ref parts)) if parts.len() == 4));
+        assert_eq!(restored.content.as_ref().unwrap().to_text(), "What is in these images?");
+        assert!(restored.text().is_none());
+
+        // Plain text messages are still sent as a string
+        let message = ChatMessage::new(Role::User, "Hello", None);
+        assert_eq!(serde_json::to_value(&message).unwrap(), serde_json::json!({ "role": "user", "content": "Hello" }));
+        assert_eq!(message.text(), Some("Hello"));
+    }
+
+    #[derive(Debug, serde::Deserialize, schemars::JsonSchema)]
+    struct Forecast {
+        location: String,
+        temperature: i32,
+        conditions: Option<String>,
+    }
+
+    fn mock_json_response(message: serde_json::Value) -> serde_json::Value {
+        let mut response = mock_chat_response();
+        response["choices"][0]["message"] = message;
+        response
+    }
+
+    #[tokio::test]
+    async fn test_mock_typed_completion() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "response_format": { "type": "json_schema", "json_schema": {
+                "name": "Forecast",
+                "strict": true,
+                "schema": {
+                    "type": "object",
+                    "required": ["conditions", "location", "temperature"],
+                    "additionalProperties": false,
+                    "properties": { "conditions": { "type": ["string", "null"] }, "temperature": { "type": "integer" } }
+                }
+            } } })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_json_response(serde_json::json!({
+                "role": "assistant", "content": r#"{"location":"Oslo","temperature":-3,"conditions":null}"#
+            }))))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_json_response(serde_json::json!({
+                "role": "assistant", "content": null, "refusal": "I can't help with that."
+            }))))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_json_response(serde_json::json!({
+                "role": "assistant", "content": r#"{"location":"Oslo"}"#
+            }))))
+            .mount(&server)
+            .await;
+
+        let ctx = get_mock_api(&server);
+        let request = ChatHistoryBuilder::default()
+            .messages(vec![ChatMessage::new(Role::User, "What's the weather like in Oslo?", None)])
+            .model("gpt-4o");
+
+        let forecast = ctx.create_chat_completion_typed::<Forecast>(request.clone()).await.unwrap();
+        assert_eq!(forecast.location, "Oslo");
+        assert_eq!(forecast.temperature, -3);
+        assert!(forecast.conditions.is_none());
+
+        let refusal = ctx.create_chat_completion_typed::<Forecast>(request.clone()).await;
+        assert!(matches!(refusal, Err(Error::Refusal(ref refusal)) if refusal == "I can't help with that."));
+
+        let mismatch = ctx.create_chat_completion_typed::<Forecast>(request).await;
+        assert!(matches!(mismatch, Err(Error::InvalidOutput { ref content, .. }) if content == r#"{"location":"Oslo"}"#));
+    }
+
+    #[tokio::test]
+    async fn test_mock_logprobs() {
+        let server = MockServer::start().await;
+        let token = |token: &str, logprob: f64| serde_json::json!({ "token": token, "logprob": logprob, "bytes": token.as_bytes(), "top_logprobs": [] });
+        let mut response = mock_chat_response();
+        response["choices"][0]["logprobs"] = serde_json::json!({ "content": [token("this", -0.1), token(" is", -0.3), token(" a", -0.2), token(" test", -1.0)], "refusal": null });
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "logprobs": true, "top_logprobs": 2 })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "cmpl-123",
+                "object": "text_completion",
+                "created": 1677652288,
+                "model": "gpt-3.5-turbo-instruct",
+                "choices": [{
+                    "text": "Hello world",
+                    "index": 0,
+                    "logprobs": {
+                        "tokens": ["Hello", " world"],
+                        "token_logprobs": [null, -0.5],
+                        "top_logprobs": [null, { " world": -0.5, " there": -1.2 }],
+                        "text_offset": [0, 5]
+                    },
+                    "finish_reason": "length"
+                }],
+                "usage": { "prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2 }
+            })))
+            .mount(&server)
+            .await;
+
+        let ctx = get_mock_api(&server);
+        let response = ctx.create_chat_completion_sync(
+            ChatHistoryBuilder::default()
+                .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+                .model("gpt-4o")
+                .logprobs(true)
+                .top_logprobs(2u32)
+        ).await.unwrap();
+        let logprobs = response.choices[0].logprobs.as_ref().unwrap();
+        assert_eq!(logprobs.content.as_ref().unwrap().len(), 4);
+        assert!((logprobs.perplexity().unwrap() - 0.4f64.exp()).abs() < 1e-9);
+        assert!((logprobs.confidence().unwrap() - (-0.4f64).exp()).abs() < 1e-9);
+        assert_eq!(logprobs.least_likely().unwrap().token, " test");
+
+        let response = ctx.create_completion(
+            CompletionRequestBuilder::default()
+                .model("gpt-3.5-turbo-instruct")
+                .prompt("Hello")
+                .echo(true)
+                .logprobs(2u64)
+                .build()
+                .unwrap()
+        ).await.unwrap();
+        let logprobs = response.choices[0].logprobs.as_ref().unwrap();
+        assert_eq!(logprobs.text_offset, vec![0, 5]);
+        assert!((logprobs.perplexity().unwrap() - 0.5f64.exp()).abs() < 1e-9);
+        assert_eq!(logprobs.token_probabilities().next(), Some(("Hello", None)));
+        assert_eq!(logprobs.top_logprobs.as_ref().unwrap()[1].as_ref().unwrap()[" there"], -1.2);
+    }
+
+    #[test]
+    fn test_streamed_logprobs() {
+        let chunk = |content: &str, logprob: f64| serde_json::from_value::<ChatCompletionDeltaResponse>(serde_json::json!({
+            "id": "chatcmpl-123", "object": "chat.completion.chunk", "created": 1677652288, "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "delta": { "content": content },
+                "logprobs": { "content": [{ "token": content, "logprob": logprob, "bytes": null, "top_logprobs": [] }], "refusal": null },
+                "finish_reason": null
+            }]
+        })).unwrap();
+
+        let mut accumulator = ChatCompletionAccumulator::new();
+        accumulator.push(&chunk("Hi", -0.25));
+        accumulator.push(&chunk("!", -0.75));
+        let response = accumulator.response();
+        let logprobs = response.choices[0].logprobs.as_ref().unwrap();
+        assert_eq!(logprobs.content.as_ref().unwrap().iter().map(|token| token.token.as_str()).collect::<String>(), "Hi!");
+        assert!((logprobs.confidence().unwrap() - (-0.5f64).exp()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_unknown_enum_values() {
+        let response = serde_json::from_value::<crate::chat::ChatCompletionSyncResponse>(serde_json::json!({
+            "id": "chatcmpl-AxO3",
+            "object": "chat.completion",
+            "created": 1738624000,
+            "model": "o3-mini-2025-01-31",
+            "choices": [
+                {
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Partial answer", "refusal": null },
+                    "finish_reason": "insufficient_system_resource"
+                },
+                {
+                    "index": 1,
+                    "message": { "role": "critic", "content": "Needs work" },
+                    "finish_reason": "tool_calls"
+                }
+            ],
+            "usage": { "prompt_tokens": 12, "completion_tokens": 5, "total_tokens": 17 }
+        })).unwrap();
+        assert_eq!(response.choices[0].finish_reason, Some(FinishReason::Unknown("insufficient_system_resource".to_string())));
+        assert_eq!(response.choices[1].message.role, Role::Unknown("critic".to_string()));
+        assert_eq!(response.choices[1].finish_reason, Some(FinishReason::ToolCalls));
+
+        // Unknown values are sent back as they were received
+        let json = serde_json::to_value(&response.choices[1].message).unwrap();
+        assert_eq!(json["role"], "critic");
+
+        let message = serde_json::from_value::<ChatMessage>(serde_json::json!({ "role": "developer", "content": [
+            { "type": "image_url", "image_url": { "url": "https://example.com/cat.png", "detail": "original" } },
+            { "type": "input_audio", "input_audio": { "data": "", "format": "flac" } },
+        ] })).unwrap();
+        assert_eq!(message.role, Role::Developer);
+        let Some(Content::Parts(parts)) = message.content else { panic!("Expected content parts") };
+        assert!(matches!(parts[0], ContentPart::ImageUrl { ref image_url } if image_url.detail == Some(ImageDetail::Unknown("original".to_string()))));
+        assert!(matches!(parts[1], ContentPart::InputAudio { ref input_audio } if input_audio.format == InputAudioFormat::Unknown("flac".to_string())));
+
+        let statuses = serde_json::from_value::<Vec<FineTuneStatus>>(serde_json::json!(["validating_files", "queued", "running", "failed", "paused"])).unwrap();
+        assert_eq!(statuses, vec![
+            FineTuneStatus::ValidatingFiles,
+            FineTuneStatus::Queued,
+            FineTuneStatus::Running,
+            FineTuneStatus::Failed,
+            FineTuneStatus::Unknown("paused".to_string()),
+        ]);
+
+        assert_eq!(crate::image::ImageSize::Unknown("2048x2048".to_string()).to_string(), "2048x2048");
+    }
+
+    #[test]
+    fn test_extra_fields() {
+        let json = serde_json::json!({
+            "id": "chatcmpl-B9MBs8",
+            "object": "chat.completion",
+            "created": 1741569952,
+            "model": "gpt-4.1-2025-04-14",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "Hello!" },
+                "finish_reason": "stop",
+                "logprobs": null,
+                "content_filter_results": { "hate": { "filtered": false } }
+            }],
+            "usage": {
+                "prompt_tokens": 19,
+                "completion_tokens": 10,
+                "total_tokens": 29,
+                "prompt_tokens_details": { "cached_tokens": 0, "audio_tokens": 0 },
+                "completion_tokens_details": { "reasoning_tokens": 0, "audio_tokens": 0, "accepted_prediction_tokens": 0, "rejected_prediction_tokens": 0 }
+            },
+            "service_tier": "default",
+            "system_fingerprint": "fp_50cad350e4",
+            "prompt_filter_results": []
+        });
+        let response = serde_json::from_value::<crate::chat::ChatCompletionSyncResponse>(json.clone()).unwrap();
+        assert_eq!(response.object, "chat.completion");
+        assert_eq!(response.system_fingerprint.as_deref(), Some("fp_50cad350e4"));
+        assert_eq!(response.service_tier.as_deref(), Some("default"));
+        assert_eq!(response.usage.prompt_tokens_details.as_ref().and_then(|details| details.cached_tokens), Some(0));
+        assert_eq!(response.usage.completion_tokens_details.as_ref().and_then(|details| details.reasoning_tokens), Some(0));
+        assert_eq!(response.extra.keys().collect::<Vec<_>>(), vec!["prompt_filter_results"]);
+        assert!(response.choices[0].extra.contains_key("content_filter_results"));
+
+        // Everything the server sent is serialized again
+        assert_eq!(serde_json::to_value(&response).unwrap(), json);
+
+        let response = serde_json::from_value::<crate::embedding::EmbeddingResponse>(serde_json::json!({
+            "object": "list",
+            "data": [{ "object": "embedding", "embedding": [0.1, -0.2], "index": 0 }],
+            "model": "text-embedding-3-small",
+            "usage": { "prompt_tokens": 2, "total_tokens": 2 },
+            "id": "emb-123"
+        })).unwrap();
+        assert_eq!(response.data[0].object, "embedding");
+        assert_eq!(response.extra["id"], "emb-123");
+
+        let response = serde_json::from_value::<ImageResponse>(serde_json::json!({
+            "created": 1713833628,
+            "data": [{ "b64_json": "iVBORw0KGgo=", "revised_prompt": "A ginger cat on a brick wall", "content_filter_results": {} }],
+            "usage": { "total_tokens": 100 }
+        })).unwrap();
+        assert_eq!(response.data[0].source(), Some(ImageSource::Base64("iVBORw0KGgo=")));
+        assert_eq!(response.data[0].revised_prompt.as_deref(), Some("A ginger cat on a brick wall"));
+        assert!(response.data[0].extra.contains_key("content_filter_results"));
+        assert!(response.extra.contains_key("usage"));
+    }
+
+    #[tokio::test]
+    async fn test_mock_azure() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/openai/deployments/chat-prod/chat/completions"))
+            .and(query_param("api-version", "2024-06-01"))
+            .and(header("api-key", "test-key"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/openai/deployments/text-embedding-3-small/embeddings"))
+            .and(query_param("api-version", "2024-06-01"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "object": "list",
+                "data": [{ "object": "embedding", "embedding": [0.5, 0.25], "index": 0 }],
+                "model": "text-embedding-3-small",
+                "usage": { "prompt_tokens": 1, "total_tokens": 1 }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/openai/models"))
+            .and(query_param("api-version", "2024-06-01"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "object": "list",
+                "data": [{ "id": "gpt-4o", "object": "model", "created": 1715367049, "owned_by": "system" }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let config = AzureConfigBuilder::default()
+            .api_version("2024-06-01")
+            .deployment("gpt-4o", "chat-prod")
+            .build()
+            .unwrap();
+        let ctx = Context::new_azure("test-key".to_string(), server.uri(), config);
+
+        let completion = ctx.create_chat_completion_sync(
+            ChatHistoryBuilder::default()
+                .model("gpt-4o")
+                .messages(vec![ChatMessage::new(Role::User, "Hello", None)])
+        ).await.unwrap();
+        assert_eq!(completion.choices[0].message.text(), Some("this is a test"));
+
+        // Models without a deployment are used as the deployment name
+        let embedding = ctx.create_embedding(
+            EmbeddingRequestBuilder::default()
+                .model("text-embedding-3-small")
+                .input("Hello")
+                .build()
+                .unwrap()
+        ).await.unwrap();
+        assert_eq!(embedding.data[0].embedding, vec![0.5, 0.25]);
+
+        let models = ctx.get_models().await.unwrap();
+        assert_eq!(models[0].id, "gpt-4o");
+
+        // Azure keys are not sent as bearer tokens
+        let requests = server.received_requests().await.unwrap();
+        assert!(requests.iter().all(|request| !request.headers.contains_key(&"authorization".into())));
+    }
+
+    fn authorizations(requests: &[wiremock::Request]) -> Vec<String> {
+        requests.iter()
+            .map(|request| request.headers.get(&"authorization".into()).unwrap().as_str().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_mock_key_pool() {
+        let server = MockServer::start().await;
+        let request = || ChatHistoryBuilder::default()
+            .model("gpt-4o")
+            .messages(vec![ChatMessage::new(Role::User, "Hello", None)]);
+
+        for (selection, expected) in [
+            (KeySelection::RoundRobin, ["Bearer key-a", "Bearer key-b", "Bearer key-a", "Bearer key-b"]),
+            // Key a has no requests left after its first use
+            (KeySelection::MostRemaining, ["Bearer key-a", "Bearer key-b", "Bearer key-b", "Bearer key-b"]),
+        ] {
+            server.reset().await;
+            Mock::given(method("POST"))
+                .and(path("/v1/chat/completions"))
+                .and(header("authorization", "Bearer key-a"))
+                .respond_with(ResponseTemplate::new(200)
+                    .insert_header("x-ratelimit-remaining-requests", "0")
+                    .insert_header("x-ratelimit-reset-requests", "1m")
+                    .set_body_json(mock_chat_response()))
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/v1/chat/completions"))
+                .and(header("authorization", "Bearer key-b"))
+                .and(header("openai-project", "proj_123"))
+                .respond_with(ResponseTemplate::new(200)
+                    .insert_header("x-ratelimit-remaining-requests", "10")
+                    .set_body_json(mock_chat_response()))
+                .mount(&server)
+                .await;
+
+            let ctx = ContextBuilder::default()
+                .auth(KeyPool::new(["key-a", "key-b"], selection))
+                .project_id("proj_123")
+                .base_url(server.uri())
+                .build()
+                .unwrap();
+            for _ in 0..4 {
+                ctx.create_chat_completion_sync(request()).await.unwrap();
+            }
+
+            assert_eq!(authorizations(&server.received_requests().await.unwrap()), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_azure_ad_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tenant/oauth2/v2.0/token"))
+            .and(wiremock::matchers::body_string_contains("grant_type=client_credentials"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "token_type": "Bearer",
+                "expires_in": 3599,
+                "access_token": "aad-token"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/openai/deployments/gpt-4o/chat/completions"))
+            .and(header("authorization", "Bearer aad-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let token = AzureAdToken::new("tenant", "client-id", "client-secret")
+            .with_token_url(format!("{}/tenant/oauth2/v2.0/token", server.uri()));
+        let ctx = ContextBuilder::default()
+            .auth(token)
+            .base_url(server.uri())
+            .azure(AzureConfigBuilder::default().build().unwrap())
+            .build()
+            .unwrap();
+
+        // The token is fetched once and reused until it is about to expire
+        for _ in 0..2 {
+            ctx.create_chat_completion_sync(
+                ChatHistoryBuilder::default()
+                    .model("gpt-4o")
+                    .messages(vec![ChatMessage::new(Role::User, "Hello", None)])
+            ).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_auth_extreme_server_values() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/tenant/oauth2/v2.0/token"))
+            .and(header("x-client", "shared"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "token_type": "Bearer",
+                "expires_in": u64::MAX,
+                "access_token": "aad-token"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200)
+                .insert_header("x-ratelimit-remaining-requests", "0")
+                .insert_header("x-ratelimit-reset-requests", "5000000000000000h")
+                .set_body_json(mock_chat_response()))
+            .mount(&server)
+            .await;
+        let request = || ChatHistoryBuilder::default()
+            .model("gpt-4o")
+            .messages(vec![ChatMessage::new(Role::User, "Hello", None)]);
+
+        // Token requests go through the given client
+        let client = reqwest::Client::builder()
+            .default_headers([(HeaderName::from_static("x-client"), HeaderValue::from_static("shared"))].into_iter().collect())
+            .build()
+            .unwrap();
+        let token = AzureAdToken::new("tenant", "client-id", "client-secret")
+            .with_token_url(format!("{}/tenant/oauth2/v2.0/token", server.uri()))
+            .with_client(client.clone());
+        let ctx = ContextBuilder::default()
+            .auth(token)
+            .base_url(server.uri())
+            .http_client(client)
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            ctx.create_chat_completion_sync(request()).await.unwrap();
+        }
+
+        let ctx = ContextBuilder::default()
+            .auth(KeyPool::new(["key-a", "key-b"], KeySelection::MostRemaining))
+            .base_url(server.uri())
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            ctx.create_chat_completion_sync(request()).await.unwrap();
+        }
+
+        // A key that never needs to be rotated is fetched once
+        let fetches = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let key = RotatingKey::new(std::time::Duration::MAX, move || {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async { Ok(String::from("vault-key")) }
+        });
+        let ctx = ContextBuilder::default().auth(key).base_url(server.uri()).build().unwrap();
+        for _ in 0..2 {
+            ctx.create_chat_completion_sync(request()).await.unwrap();
+        }
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_auth_rejection_during_refresh() {
+        use crate::auth::{AuthProvider, Credential};
+        use std::sync::{atomic::{AtomicUsize, Ordering}, Arc};
+
+        // The second fetch waits until released and still returns the old key, as a vault might before rotating it
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(tokio::sync::Notify::new());
+        let (counter, gate) = (fetches.clone(), release.clone());
+        let key = Arc::new(RotatingKey::new(Duration::from_millis(200), move || {
+            let (fetch, gate) = (counter.fetch_add(1, Ordering::SeqCst), gate.clone());
+            async move {
+                match fetch {
+                    0 => Ok(String::from("key-1")),
+                    1 => {
+                        gate.notified().await;
+                        Ok(String::from("key-1"))
+                    },
+                    _ => Ok(String::from("key-2")),
+                }
+            }
+        }));
+
+        assert_eq!(key.credential().await.unwrap(), Credential::ApiKey(String::from("key-1")));
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let refresh = tokio::spawn({
+            let key = key.clone();
+            async move { key.credential().await }
+        });
+        while fetches.load(Ordering::SeqCst) < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        // Rejected while the refresh holds the cache, which must not lose the rejection
+        key.observe(&Credential::ApiKey(String::from("key-1")), reqwest::StatusCode::UNAUTHORIZED, &Default::default());
+        release.notify_one();
+        refresh.await.unwrap().unwrap();
+        assert_eq!(key.credential().await.unwrap(), Credential::ApiKey(String::from("key-2")));
+    }
+
+    #[tokio::test]
+    async fn test_mock_config_from_env() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("authorization", "Bearer env-key"))
+            .and(header("openai-organization", "org-123"))
+            .and(header("openai-project", "proj_123"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let base_url = format!("{}/v1", server.uri());
+        let vars = std::collections::HashMap::from([
+            ("OPENAI_API_KEY", "env-key"),
+            ("OPENAI_ORG_ID", "org-123"),
+            ("OPENAI_PROJECT_ID", "proj_123"),
+            ("OPENAI_BASE_URL", base_url.as_str()),
+            ("OPENAI_TIMEOUT", "30"),
+            ("OPENAI_CONNECT_TIMEOUT", "2.5"),
+            ("OPENAI_MAX_RETRIES", "1"),
+            ("OPENAI_INITIAL_BACKOFF", "250ms"),
+            ("OPENAI_MAX_BACKOFF", ""),
+        ]);
+        let config = ContextConfig::from_lookup(|name| vars.get(name).map(|value| value.to_string())).unwrap();
+        assert_eq!(config.timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.connect_timeout, Some(Duration::from_millis(2500)));
+        assert_eq!(config.initial_backoff, Some(Duration::from_millis(250)));
+        // Empty variables are ignored
+        assert_eq!(config.max_backoff, None);
+
+        let ctx = config.build().unwrap();
+        assert_eq!(ctx.base_url(), server.uri());
+        assert_eq!(ctx.path_prefix(), "/v1");
+        assert_eq!(ctx.retry_policy().max_retries, 1);
+        for base_url in ["https://example.com/", "https://example.com"] {
+            let ctx = ContextConfig { api_key: Some("key".to_string()), base_url: Some(base_url.to_string()), ..Default::default() }
+                .build()
+                .unwrap();
+            assert_eq!(ctx.base_url(), "https://example.com");
+            assert_eq!(ctx.path_prefix(), "/v1");
+        }
+        ctx.create_chat_completion_sync(
+            ChatHistoryBuilder::default()
+                .model("gpt-4o")
+                .messages(vec![ChatMessage::new(Role::User, "Hello", None)])
+        ).await.unwrap();
+
+        // Every problem is reported at once
+        let vars = std::collections::HashMap::from([
+            ("OPENAI_TIMEOUT", "soon"),
+            ("OPENAI_MAX_RETRIES", "-1"),
+            ("OPENAI_BASE_URL", "api.openai.com"),
+        ]);
+        let Err(Error::Config(message)) = ContextConfig::from_lookup(|name| vars.get(name).map(|value| value.to_string())) else {
+            panic!("Expected a configuration error");
+        };
+        assert!(message.contains("OPENAI_TIMEOUT must be seconds or a duration such as `1m30s`, not `soon`"), "{message}");
+        assert!(message.contains("OPENAI_MAX_RETRIES must be a non-negative integer, not `-1`"), "{message}");
+        assert!(message.contains("OPENAI_API_KEY is not set"), "{message}");
+        assert!(!message.contains("no API key"), "{message}");
+        assert!(message.contains("`base_url` must start with http:// or https://"), "{message}");
+    }
+
+    #[test]
+    fn test_config_profiles() {
+        let dir = std::env::temp_dir().join(format!("openai_rs_config_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key_file = dir.join("key.txt");
+        std::fs::write(&key_file, "file-key\n").unwrap();
+
+        let toml_path = dir.join("profiles.toml");
+        std::fs::write(&toml_path, format!(r#"
+            [default]
+            api_key = "default-key"
+            timeout = "1m30s"
+            max_retries = 0
+
+            [azure]
+            api_key_file = {key_file:?}
+            base_url = "https://my-resource.openai.azure.com"
+            azure = {{ api_version = "2024-06-01", deployments = {{ "gpt-4o" = "chat-prod" }} }}
+
+            [broken]
+            api_key = "key"
+            api_key_env = "OPENAI_API_KEY"
+            initial_backoff = 10
+            max_backoff = 5
+        "#)).unwrap();
+
+        let default = ContextConfig::from_file(&toml_path, "default").unwrap();
+        assert_eq!(default.timeout, Some(Duration::from_secs(90)));
+        assert_eq!(default.build().unwrap().retry_policy().max_retries, 0);
+
+        let ctx = Context::from_profile(&toml_path, "azure").unwrap();
+        let azure = ctx.azure().unwrap();
+        assert_eq!(azure.api_version, "2024-06-01");
+        assert_eq!(azure.deployment(Some("gpt-4o")), Some("chat-prod"));
+
+        let Err(Error::Config(message)) = ContextConfig::from_file(&toml_path, "broken") else { panic!("Expected a configuration error") };
+        assert!(message.contains("only one of `api_key`, `api_key_env` and `api_key_file` may be set"), "{message}");
+        assert!(message.contains("`initial_backoff` must not be longer than `max_backoff`"), "{message}");
+
+        let Err(Error::Config(message)) = ContextConfig::from_file(&toml_path, "staging") else { panic!("Expected a configuration error") };
+        assert!(message.ends_with("has no profile `staging`. Available profiles: azure, broken, default"), "{message}");
+
+        let json_path = dir.join("profiles.json");
+        std::fs::write(&json_path, r#"{ "default": { "api_key": "json-key", "temperature": 0.5 } }"#).unwrap();
+        let Err(Error::Config(message)) = ContextConfig::from_file(&json_path, "default") else { panic!("Expected a configuration error") };
+        assert!(message.contains("unknown field `temperature`"), "{message}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tags every request and records the endpoint, attempt and status of each response
+    #[derive(Debug, Default)]
+    struct RecordingMiddleware {
+        calls: std::sync::Mutex<Vec<(String, u32, Option<u16>)>>,
+    }
+
+    impl Middleware for RecordingMiddleware {
+        fn before_send(&self, request: &mut OutgoingRequest) {
+            request.headers.insert("x-trace-id", HeaderValue::from_static("trace-1"));
+            if let Some(ref mut body) = request.body {
+                body["user"] = "middleware".into();
+            }
+        }
+
+        fn after_receive(&self, request: &OutgoingRequest, response: std::result::Result<&ReceivedResponse<'_>, &Error>) {
+            let status = response.ok().map(|response| response.status.as_u16());
+            self.calls.lock().unwrap().push((request.endpoint.clone(), request.attempt, status));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_middleware() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("x-trace-id", "trace-1"))
+            .and(body_partial_json(serde_json::json!({ "stream": false, "user": "middleware" })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("x-trace-id", "trace-1"))
+            .and(body_partial_json(serde_json::json!({ "stream": true, "user": "middleware" })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(mock_chat_stream_body(), "text/event-stream"))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/files"))
+            .and(header("x-trace-id", "trace-1"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "file-abc123",
+                "object": "file",
+                "bytes": 5,
+                "created_at": 1677610602,
+                "filename": "data.jsonl",
+                "purpose": "fine-tune"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let recorder = Arc::new(RecordingMiddleware::default());
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .retry_policy(fast_retry_policy())
+            .middleware(recorder.clone())
+            .build()
+            .unwrap();
+
+        let request = || ChatHistoryBuilder::default()
+            .model("gpt-4o")
+            .messages(vec![ChatMessage::new(Role::User, "Hello", None)]);
+        ctx.create_chat_completion_sync(request()).await.unwrap();
+        let stream = ctx.create_chat_completion_streamed(request()).await.unwrap();
+        assert_eq!(stream.collect_response().await.unwrap().choices[0].message.text(), Some("this is a test"));
+        ctx.upload_file(crate::util::FileResource::Data(b"{}\n{}".to_vec()), "data.jsonl".to_string(), "fine-tune".to_string()).await.unwrap();
+
+        assert_eq!(*recorder.calls.lock().unwrap(), vec![
+            ("chat/completions".to_string(), 0, Some(500)),
+            ("chat/completions".to_string(), 1, Some(200)),
+            ("chat/completions".to_string(), 0, Some(200)),
+            ("files".to_string(), 0, Some(200)),
+        ]);
+    }
+
+    #[cfg(feature = "tracing")]
+    type Fields = std::collections::HashMap<String, String>;
+
+    /// Subscriber that keeps every span and event, for checking what the `tracing` feature emits
+    #[cfg(feature = "tracing")]
+    #[derive(Debug, Clone, Default)]
+    struct CapturingSubscriber {
+        spans: Arc<std::sync::Mutex<Vec<(&'static str, Fields)>>>,
+        /// Events, with the id of their parent span as the `parent` field
+        events: Arc<std::sync::Mutex<Vec<Fields>>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    struct FieldRecorder<'a>(&'a mut Fields);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for FieldRecorder<'_> {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for CapturingSubscriber {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut spans = self.spans.lock().unwrap();
+            let mut fields = Fields::new();
+            span.record(&mut FieldRecorder(&mut fields));
+            spans.push((span.metadata().name(), fields));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            values.record(&mut FieldRecorder(&mut self.spans.lock().unwrap()[span.into_u64() as usize - 1].1));
+        }
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = Fields::new();
+            event.record(&mut FieldRecorder(&mut fields));
+            if let Some(parent) = event.parent() {
+                fields.insert("parent".to_string(), parent.into_u64().to_string());
+            }
+            self.events.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_mock_tracing() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "stream": false })))
+            .respond_with(ResponseTemplate::new(200)
+                .insert_header("x-request-id", "req_123")
+                .set_body_json(mock_chat_response()))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "stream": true })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(mock_chat_stream_body(), "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        let subscriber = CapturingSubscriber::default();
+        let _guard = tracing::subscriber::set_default(subscriber.clone());
+
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .retry_policy(fast_retry_policy())
+            .build()
+            .unwrap();
+        let request = || ChatHistoryBuilder::default()
+            .model("gpt-4o")
+            .messages(vec![ChatMessage::new(Role::User, "Hello", None)]);
+        ctx.create_chat_completion_sync(request()).await.unwrap();
+        ctx.create_chat_completion_streamed(request()).await.unwrap().collect_response().await.unwrap();
+
+        let spans = subscriber.spans.lock().unwrap();
+        let requests = spans.iter().enumerate().filter(|(_, (name, _))| *name == "openai.request").collect::<Vec<_>>();
+        assert_eq!(requests.len(), 2);
+
+        let (_, (_, sync)) = requests[0];
+        assert_eq!(sync["endpoint"], "chat/completions");
+        assert_eq!(sync["model"], "gpt-4o");
+        assert_eq!(sync["request_id"], "req_123");
+        assert_eq!(sync["status"], "200");
+        assert_eq!(sync["retries"], "1");
+        assert_eq!(sync["total_tokens"], "13");
+        assert!(sync.contains_key("duration_ms"));
+
+        let (index, (_, streamed)) = requests[1];
+        assert_eq!(streamed["retries"], "0");
+        assert_eq!(streamed["chunks"], "4");
+        let chunk_events = subscriber.events.lock().unwrap().iter()
+            .filter(|fields| fields.get("parent") == Some(&(index + 1).to_string()) && fields.get("message").is_some_and(|message| message == "received chunk"))
+            .count();
+        assert_eq!(chunk_events, 4);
+    }
+
+    fn fast_retry_policy() -> RetryPolicy {
+        RetryPolicyBuilder::default()
+            .initial_backoff(Duration::from_millis(1))
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mock_retry_rate_limit() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(429)
+                .insert_header("retry-after-ms", "10")
+                .set_body_json(serde_json::json!({
+                    "error": { "message": "Rate limit reached", "type": "requests", "param": null, "code": "rate_limit_exceeded" }
+                })))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .retry_policy(fast_retry_policy())
+            .build()
+            .unwrap();
+
+        let completion = ctx.create_chat_completion_sync(
+            ChatHistoryBuilder::default()
+                .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+                .model("gpt-3.5-turbo")
+        ).await;
+        assert!(completion.is_ok(), "Could not create completion: {}", completion.unwrap_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_retry_after_invalid() {
+        let server = MockServer::start().await;
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .retry_policy(RetryPolicyBuilder::default()
+                .initial_backoff(Duration::from_millis(1))
+                .max_retry_after(Duration::from_millis(10))
+                .build()
+                .unwrap())
+            .build()
+            .unwrap();
+
+        // Malformed and negative delays fall back to the backoff, huge ones are capped by `max_retry_after`
+        for (name, value) in [("retry-after", "soon"), ("retry-after", "-1"), ("retry-after", "NaN"), ("retry-after", "inf"), ("retry-after", "1e300"), ("retry-after-ms", "-5"), ("retry-after-ms", "1e300")] {
+            server.reset().await;
+            Mock::given(method("GET"))
+                .and(path("/v1/models"))
+                .respond_with(ResponseTemplate::new(503).insert_header(name, value))
+                .up_to_n_times(1)
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/v1/models"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "object": "list", "data": [] })))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let start = std::time::Instant::now();
+            let models = ctx.get_models().await;
+            assert!(models.is_ok(), "Request with {name}: {value} failed: {}", models.unwrap_err());
+            assert!(start.elapsed() < Duration::from_secs(5), "Request with {name}: {value} waited too long");
+            server.verify().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_retry_multipart() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/images/variations"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/images/variations"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "created": 1589478378,
+                "data": [{ "url": "https://example.com/image.png" }]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .retry_policy(fast_retry_policy())
+            .build()
+            .unwrap();
+
+        let image = ctx.create_image_variation(
+            ImageVariationRequestBuilder::default()
+                .image(File::open("clown_original.png").await.unwrap())
+                .build()
+                .unwrap()
+        ).await;
+        assert!(image.is_ok(), "Could not get image: {}", image.unwrap_err());
+
+        // The file must have been sent in full on both attempts
+        let file_size = std::fs::metadata("clown_original.png").unwrap().len() as usize;
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|request| request.body.len() > file_size));
+    }
+
+    #[tokio::test]
+    async fn test_mock_no_retry_quota() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v1/models"))
+            .respond_with(ResponseTemplate::new(429).set_body_json(serde_json::json!({
+                "error": { "message": "You exceeded your current quota", "type": "insufficient_quota", "param": null, "code": "insufficient_quota" }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .retry_policy(fast_retry_policy())
+            .build()
+            .unwrap();
+
+        let error = ctx.get_models().await.unwrap_err();
+        assert!(error.is_quota_exceeded() && !error.is_rate_limit(), "Unexpected error: {error}");
+    }
+
+    #[tokio::test]
+    async fn test_mock_rate_limiter_learns_headers() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200)
+                .insert_header("x-ratelimit-limit-requests", "600")
+                .insert_header("x-ratelimit-remaining-requests", "0")
+                .set_body_json(mock_chat_response()))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let limiter = Arc::new(RateLimiterBuilder::default().build().unwrap());
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .rate_limiter(limiter.clone())
+            .build()
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        for _ in 0..2 {
+            let completion = ctx.create_chat_completion_sync(
+                ChatHistoryBuilder::default()
+                    .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+                    .model("gpt-3.5-turbo")
+            ).await;
+            assert!(completion.is_ok(), "Could not create completion: {}", completion.unwrap_err());
+        }
+
+        // No budget was left after the first request, so the second had to wait for one request's worth of refill
+        assert!(start.elapsed() >= Duration::from_millis(90), "Second request was not delayed");
+    }
+
+    #[tokio::test]
+    async fn test_mock_response_metadata() {
+        let server = MockServer::start().await;
+        let with_headers = |response: ResponseTemplate| response
+            .insert_header("x-request-id", "req_123")
+            .insert_header("openai-processing-ms", "250")
+            .insert_header("openai-organization", "org-test")
+            .insert_header("x-ratelimit-limit-requests", "500")
+            .insert_header("x-ratelimit-remaining-requests", "499")
+            .insert_header("x-ratelimit-remaining-tokens", "9000")
+            .insert_header("x-ratelimit-reset-tokens", "6m0s");
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "stream": false })))
+            .respond_with(with_headers(ResponseTemplate::new(200).set_body_json(mock_chat_response())))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "stream": true })))
+            .respond_with(with_headers(ResponseTemplate::new(200).set_body_raw(mock_chat_stream_body(), "text/event-stream")))
+            .mount(&server)
+            .await;
+
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .retry_policy(fast_retry_policy())
+            .build()
+            .unwrap();
+        let request = || ChatHistoryBuilder::default()
+            .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+            .model("gpt-3.5-turbo");
+
+        let completion = ctx.create_chat_completion_sync_with_metadata(request()).await.unwrap();
+        assert_eq!(completion.choices.len(), 1);
+        let metadata = &completion.metadata;
+        assert_eq!(metadata.status, 200);
+        assert_eq!(metadata.request_id.as_deref(), Some("req_123"));
+        assert_eq!(metadata.processing_time, Some(Duration::from_millis(250)));
+        assert_eq!(metadata.organization.as_deref(), Some("org-test"));
+        assert_eq!(metadata.retries, 1);
+        assert_eq!(metadata.rate_limits.limit_requests, Some(500));
+        assert_eq!(metadata.rate_limits.remaining_requests, Some(499));
+        assert_eq!(metadata.rate_limits.remaining_tokens, Some(9000));
+        assert_eq!(metadata.rate_limits.reset_tokens, Some(Duration::from_secs(360)));
+        assert_eq!(metadata.rate_limits.limit_tokens, None);
+
+        // Metadata is available before the first chunk has been read
+        let mut stream = ctx.create_chat_completion_streamed_with_metadata(request()).await.unwrap();
+        assert_eq!(stream.metadata.request_id.as_deref(), Some("req_123"));
+        assert_eq!(stream.metadata.retries, 0);
+        let mut chunks = 0;
+        while let Some(chunk) = stream.next().await {
+            chunk.unwrap();
+            chunks += 1;
+        }
+        assert!(chunks > 0, "No chunks received");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_reconcile() {
+        let limiter = RateLimiterBuilder::default()
+            .tokens_per_minute(1000)
+            .model_limits("gpt-4", RateLimits { requests_per_minute: Some(1), tokens_per_minute: None })
+            .build()
+            .unwrap();
+
+        assert_eq!(limiter.limits_for("gpt-4").requests_per_minute, Some(1));
+        assert_eq!(limiter.limits_for("gpt-3.5-turbo").tokens_per_minute, Some(1000));
+
+        // Only 100 of the 500 reserved tokens were used, so 800 more fit right away
+        let permit = limiter.acquire("gpt-3.5-turbo", 500).await;
+        limiter.reconcile(permit, 100);
+        let permit = tokio::time::timeout(Duration::from_millis(50), limiter.acquire("gpt-3.5-turbo", 800)).await;
+        assert!(permit.is_ok(), "Unused tokens were not returned to the budget");
+    }
+
+    #[tokio::test]
+    async fn test_mock_credential_after_rate_limit() {
+        use crate::auth::{AuthProvider, Credential, CredentialFuture};
+
+        /// Records when each credential was handed out
+        #[derive(Debug, Default)]
+        struct TimedKey(std::sync::Mutex<Vec<std::time::Instant>>);
+
+        impl AuthProvider for TimedKey {
+            fn credential(&self) -> CredentialFuture<'_> {
+                self.0.lock().unwrap().push(std::time::Instant::now());
+                Box::pin(std::future::ready(Ok(Credential::ApiKey("test-key".to_string()))))
+            }
+        }
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200)
+                .insert_header("x-ratelimit-limit-requests", "600")
+                .insert_header("x-ratelimit-remaining-requests", "0")
+                .set_body_json(mock_chat_response()))
+            .mount(&server)
+            .await;
+
+        let key = Arc::new(TimedKey::default());
+        let ctx = ContextBuilder::default()
+            .shared_auth(key.clone())
+            .base_url(server.uri())
+            .rate_limiter(Arc::new(RateLimiterBuilder::default().build().unwrap()))
+            .build()
+            .unwrap();
+        let start = std::time::Instant::now();
+        for _ in 0..2 {
+            ctx.create_chat_completion_sync(
+                ChatHistoryBuilder::default()
+                    .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+                    .model("gpt-3.5-turbo")
+            ).await.unwrap();
+        }
+
+        // The second request waited for budget before asking for its credential
+        let fetched = key.0.lock().unwrap().clone();
+        assert_eq!(fetched.len(), 2);
+        assert!(fetched[1] - start >= Duration::from_millis(90), "Credential was fetched before waiting for the rate limiter");
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        use crate::{rate_limit::estimate_tokens, request::ApiRequest};
+
+        let estimate = |body: serde_json::Value| estimate_tokens(&ApiRequest::post("chat/completions").json(&body).unwrap());
+        // 12 characters of role and content make 3 prompt tokens
+        let messages = serde_json::json!([{ "role": "user", "content": "12345678" }]);
+        assert_eq!(estimate(serde_json::json!({ "messages": messages, "max_tokens": 10, "n": 2 })), 23);
+        assert_eq!(estimate(serde_json::json!({ "messages": messages, "max_completion_tokens": 10 })), 13);
+        // Huge limits saturate instead of wrapping around to small estimates
+        assert_eq!(estimate(serde_json::json!({ "messages": messages, "max_tokens": u64::MAX, "n": 3 })), u32::MAX);
+        assert_eq!(estimate(serde_json::json!({ "messages": messages, "max_completion_tokens": 1u64 << 32 })), u32::MAX);
+    }
+
+    #[tokio::test]
+    async fn test_mock_rate_limiter_zero_limits() {
+        assert!(RateLimiterBuilder::default().requests_per_minute(0).build().is_err());
+        assert!(RateLimiterBuilder::default().model_limits("gpt-4", RateLimits { requests_per_minute: None, tokens_per_minute: Some(0) }).build().is_err());
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200)
+                .insert_header("x-ratelimit-limit-requests", "0")
+                .insert_header("x-ratelimit-limit-tokens", "0")
+                .set_body_json(mock_chat_response()))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .rate_limiter(Arc::new(RateLimiterBuilder::default().build().unwrap()))
+            .build()
+            .unwrap();
+        for _ in 0..3 {
+            let completion = tokio::time::timeout(Duration::from_secs(2), ctx.create_chat_completion_sync(
+                ChatHistoryBuilder::default()
+                    .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+                    .model("gpt-3.5-turbo")
+            )).await.expect("Zero limits from headers were adopted");
+            assert!(completion.is_ok(), "Could not create completion: {}", completion.unwrap_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_rate_limiter_settles_failures() {
+        let server = MockServer::start().await;
+        let stream_body = |data: &[&str]| data.iter().map(|data| format!("data: {data}\n\n")).collect::<String>();
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "stream": false })))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "error": { "message": "Invalid request", "type": "invalid_request_error", "param": null, "code": null }
+            })))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "stream": false })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "stream": true })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(stream_body(&[
+                r#"{"error":{"message":"Server overloaded","type":"server_error","param":null,"code":null}}"#,
+                "[DONE]",
+            ]), "text/event-stream"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "stream": true })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(stream_body(&[
+                r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-3.5-turbo","choices":[{"index":0,"delta":{"content":"this is a test"},"finish_reason":"stop"}]}"#,
+                r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-3.5-turbo","choices":[],"usage":{"prompt_tokens":5,"completion_tokens":5,"total_tokens":10}}"#,
+                "[DONE]",
+            ]), "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .rate_limiter(Arc::new(RateLimiterBuilder::default().tokens_per_minute(1000).learn_from_headers(false).build().unwrap()))
+            .build()
+            .unwrap();
+        // Each request reserves most of the budget, so every one has to give it back for the next to go through
+        let request = || ChatHistoryBuilder::default()
+            .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+            .model("gpt-3.5-turbo")
+            .max_tokens(900u64);
+        let within_budget = Duration::from_secs(2);
+
+        let rejected = tokio::time::timeout(within_budget, ctx.create_chat_completion_sync(request())).await.expect("Rejected request waited for budget");
+        assert!(rejected.is_err());
+
+        let mut failed_stream = tokio::time::timeout(within_budget, ctx.create_chat_completion_streamed(request())).await.expect("Failed stream waited for budget").unwrap();
+        assert!(failed_stream.next().await.unwrap().is_err());
+        assert!(failed_stream.next().await.is_none());
+
+        let mut stream = tokio::time::timeout(within_budget, ctx.create_chat_completion_streamed(request())).await.expect("Stream waited for budget").unwrap();
+        while let Some(chunk) = stream.next().await {
+            chunk.unwrap();
+        }
+
+        let completion = tokio::time::timeout(within_budget, ctx.create_chat_completion_sync(request())).await.expect("Stream usage was not settled");
+        assert!(completion.is_ok(), "Could not create completion: {}", completion.unwrap_err());
+    }
+
+    #[test]
+    fn test_parse_reset_duration() {
+        use crate::util::parse_duration;
+
+        assert_eq!(parse_duration("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_duration("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(parse_duration("1h2m3.5s"), Some(Duration::from_secs_f64(3723.5)));
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration(&format!("{}s", "9".repeat(400))), None);
+    }
+
+    #[tokio::test]
+    async fn test_mock_function_call() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({
+                "function_call": { "name": "get_weather" },
+                "functions": [{ "name": "get_weather" }]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "id": "chatcmpl-123",
+                "object": "chat.completion",
+                "created": 1677652288,
+                "model": "gpt-3.5-turbo-0613",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "function_call": { "name": "get_weather", "arguments": "{\"location\": \"Stockholm\"}" }
+                    },
+                    "finish_reason": "function_call"
+                }],
+                "usage": { "prompt_tokens": 82, "completion_tokens": 18, "total_tokens": 100 }
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let functions = vec![FunctionDefinition::new(
+            "get_weather",
+            Some("Get the current weather in a location".to_string()),
+            serde_json::json!({
+                "type": "object",
+                "properties": { "location": { "type": "string" } },
+                "required": ["location"]
+            })
+        )];
+        let mut messages = vec![ChatMessage::new(Role::User, "What's the weather like in Stockholm?", None)];
+
+        let completion = get_mock_api(&server).create_chat_completion_sync(
+            ChatHistoryBuilder::default()
+                .messages(messages.clone())
+                .model("gpt-3.5-turbo-0613")
+                .functions(functions.clone())
+                .function_call(FunctionCallMode::Force("get_weather".to_string()))
+        ).await;
+        assert!(completion.is_ok(), "Could not create completion: {}", completion.unwrap_err());
+
+        let choice = completion.unwrap().choices.remove(0);
+        assert!(matches!(choice.finish_reason, Some(FinishReason::FunctionCall)));
+        let call = choice.message.function_call.clone().unwrap();
+        assert_eq!(call.name, "get_weather");
+        assert_eq!(call.parse_arguments::<serde_json::Value>().unwrap()["location"], "Stockholm");
+
+        // Send the result back
+        messages.push(choice.message);
+        messages.push(ChatMessage::function_result("get_weather", r#"{"temperature": 12}"#));
+        server.reset().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({
+                "messages": [
+                    { "role": "user" },
+                    { "role": "assistant", "content": null, "function_call": { "name": "get_weather" } },
+                    { "role": "function", "name": "get_weather", "content": "{\"temperature\": 12}" }
+                ]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let completion = get_mock_api(&server).create_chat_completion_sync(
+            ChatHistoryBuilder::default()
+                .messages(messages)
+                .model("gpt-3.5-turbo-0613")
+                .functions(functions)
+        ).await;
+        assert!(completion.is_ok(), "Could not create completion: {}", completion.unwrap_err());
+    }
+
+    #[test]
+    fn test_function_call_delta() {
+        let chunk = r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-3.5-turbo-0613","choices":[{"index":0,"delta":{"function_call":{"arguments":"{\"loc"}},"finish_reason":null}]}"#
+            .parse::<ChatCompletionDeltaResponse>()
+            .unwrap();
+
+        let call = chunk.choices[0].delta.function_call.as_ref().unwrap();
+        assert!(call.name.is_none());
+        assert_eq!(call.arguments.as_deref(), Some("{\"loc"));
+    }
+
+    fn mock_tool_calls_response() -> serde_json::Value {
+        serde_json::json!({
+            "id": "chatcmpl-123",
+            "object": "chat.completion",
+            "created": 1677652288,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [
+                        { "id": "call_1", "type": "function", "function": { "name": "get_weather", "arguments": "{\"location\": \"Stockholm\"}" } },
+                        { "id": "call_2", "type": "function", "function": { "name": "get_weather", "arguments": "{\"location\": \"Oslo\"}" } }
+                    ]
+                },
+                "finish_reason": "tool_calls"
+            }],
+            "usage": { "prompt_tokens": 82, "completion_tokens": 36, "total_tokens": 118 }
+        })
+    }
+
+    fn weather_tool() -> Tool {
+        Tool::function(FunctionDefinition::new(
+            "get_weather",
+            Some("Get the current weather in a location".to_string()),
+            serde_json::json!({
+                "type": "object",
+                "properties": { "location": { "type": "string" } },
+                "required": ["location"]
+            })
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_mock_tool_calls() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({
+                "tools": [{ "type": "function", "function": { "name": "get_weather" } }],
+                "tool_choice": "required",
+                "parallel_tool_calls": true
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_tool_calls_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let completion = get_mock_api(&server).create_chat_completion_sync(
+            ChatHistoryBuilder::default()
+                .messages(vec![ChatMessage::new(Role::User, "What's the weather like in Stockholm and Oslo?", None)])
+                .model("gpt-4o")
+                .tools(vec![weather_tool()])
+                .tool_choice(ToolChoice::Required)
+                .parallel_tool_calls(true)
+        ).await;
+        assert!(completion.is_ok(), "Could not create completion: {}", completion.unwrap_err());
+
+        let choice = completion.unwrap().choices.remove(0);
+        assert!(matches!(choice.finish_reason, Some(FinishReason::ToolCalls)));
+        let calls = choice.message.tool_calls.unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1].id, "call_2");
+
+        let result = serde_json::to_value(ChatMessage::tool_result("call_2", "12 degrees")).unwrap();
+        assert_eq!(result, serde_json::json!({ "role": "tool", "content": "12 degrees", "tool_call_id": "call_2" }));
+    }
+
+    #[test]
+    fn test_tool_call_deltas() {
+        let chunks = [
+            r#"{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather","arguments":""}}"#,
+            r#"{"index":1,"id":"call_2","type":"function","function":{"name":"get_weather","arguments":""}}"#,
+            r#"{"index":0,"function":{"arguments":"{\"location\": "}}"#,
+            r#"{"index":1,"function":{"arguments":"{\"location\": \"Oslo\"}"}}"#,
+            r#"{"index":0,"function":{"arguments":"\"Stockholm\"}"}}"#,
+        ];
+
+        let mut calls = Vec::new();
+        for chunk in chunks {
+            serde_json::from_str::<DeltaToolCall>(chunk).unwrap().merge_into(&mut calls);
+        }
+
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].function.arguments, r#"{"location": "Stockholm"}"#);
+        assert_eq!(calls[1].function.arguments, r#"{"location": "Oslo"}"#);
+    }
+
+    #[derive(serde::Deserialize, schemars::JsonSchema)]
+    struct WeatherArgs {
+        location: String,
+    }
+
+    fn weather_registry() -> ToolRegistry {
+        let mut registry = ToolRegistry::new();
+        registry.register("get_weather", "Get the current weather in a location", |args: WeatherArgs| async move {
+            match args.location.as_str() {
+                "Stockholm" => Ok(serde_json::json!({ "temperature": 12 })),
+                location => Err(format!("No weather data for {location}")),
+            }
+        });
+        registry
+    }
+
+    #[test]
+    fn test_tool_registry_order() {
+        let mut registry = weather_registry();
+        for name in ["set_alarm", "add_reminder", "lookup_contact"] {
+            registry.register(name, "", |_: WeatherArgs| async move { Ok::<_, String>("done") });
+        }
+
+        let names = registry.tools().into_iter().map(|Tool::Function { function }| function.name).collect::<Vec<_>>();
+        assert_eq!(names, ["add_reminder", "get_weather", "lookup_contact", "set_alarm"]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_tool_loop() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({
+                "tools": [{
+                    "type": "function",
+                    "function": {
+                        "name": "get_weather",
+                        "parameters": { "type": "object", "properties": { "location": { "type": "string" } }, "required": ["location"] }
+                    }
+                }]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_tool_calls_response()))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let result = get_mock_api(&server).run_tool_loop(
+            ChatHistoryBuilder::default()
+                .messages(vec![ChatMessage::new(Role::User, "What's the weather like in Stockholm and Oslo?", None)])
+                .model("gpt-4o"),
+            &weather_registry(),
+            ToolLoopOptions::default()
+        ).await;
+        assert!(result.is_ok(), "Tool loop failed: {}", result.unwrap_err());
+
+        let result = result.unwrap();
+        assert_eq!(result.stop, ToolLoopStop::Completed);
+        assert_eq!(result.trace.len(), 2);
+
+        let executions = &result.trace[0].executions;
+        assert_eq!(executions.len(), 2);
+        assert!(executions[0].success);
+        assert_eq!(executions[0].output, r#"{"temperature":12}"#);
+        assert!(!executions[1].success);
+        assert_eq!(executions[1].output, "Error: No weather data for Oslo");
+
+        // user, assistant tool calls, two tool results, final answer
+        let messages = &result.history.messages;
+        assert_eq!(messages.len(), 5);
+        assert_eq!(messages[3].tool_call_id.as_deref(), Some("call_2"));
+        assert_eq!(messages[4].text(), Some("this is a test"));
+    }
+
+    #[tokio::test]
+    async fn test_mock_tool_loop_max_iterations() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_tool_calls_response()))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let result = get_mock_api(&server).run_tool_loop(
+            ChatHistoryBuilder::default()
+                .messages(vec![ChatMessage::new(Role::User, "What's the weather like in Stockholm and Oslo?", None)])
+                .model("gpt-4o"),
+            &weather_registry(),
+            ToolLoopOptionsBuilder::default().max_iterations(2usize).build().unwrap()
+        ).await.unwrap();
+
+        assert_eq!(result.stop, ToolLoopStop::MaxIterations);
+        assert_eq!(result.trace.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mock_stream_accumulator() {
+        let server = MockServer::start().await;
+        let body = [
+            r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":""},"finish_reason":null},{"index":1,"delta":{"role":"assistant","content":null,"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather","arguments":""}}]},"finish_reason":null}],"usage":null}"#,
+            r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-4o","choices":[{"index":0,"delta":{"content":"Sunny"},"finish_reason":null},{"index":1,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"location\":\"Oslo\"}"}}]},"finish_reason":null}],"usage":null}"#,
+            r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-4o","choices":[{"index":0,"delta":{"content":" today"},"finish_reason":"stop"},{"index":1,"delta":{},"finish_reason":"tool_calls"}],"usage":null}"#,
+            r#"{"id":"chatcmpl-123","object":"chat.completion.chunk","created":1677652288,"model":"gpt-4o","choices":[],"usage":{"prompt_tokens":9,"completion_tokens":12,"total_tokens":21}}"#,
+            "[DONE]",
+        ].iter().map(|data| format!("data: {data}\n\n")).collect::<String>();
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(body_partial_json(serde_json::json!({ "stream": true, "stream_options": { "include_usage": true } })))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(body, "text/event-stream"))
+            .mount(&server)
+            .await;
+
+        let stream = get_mock_api(&server).create_chat_completion_streamed(
+            ChatHistoryBuilder::default()
+                .messages(vec![ChatMessage::new(Role::User, "What's the weather like in Oslo?", None)])
+                .model("gpt-4o")
+                .n(2u32)
+                .stream_options(StreamOptions { include_usage: true })
+        ).await.unwrap();
+
+        let mut stream = stream.accumulate();
+        let mut chunks = 0;
+        while let Some(chunk) = stream.next().await {
+            assert!(chunk.is_ok(), "Could not get completion: {}", chunk.unwrap_err());
+            chunks += 1;
+        }
+        assert_eq!(chunks, 4);
+        assert!(stream.accumulator().is_finished());
+
+        let response = stream.into_response();
+        assert_eq!(response.id, "chatcmpl-123");
+        assert_eq!(response.usage.total_tokens, 21);
+        assert_eq!(response.choices.len(), 2);
+        assert_eq!(response.choices[0].message.text(), Some("Sunny today"));
+        assert_eq!(response.choices[0].finish_reason, Some(FinishReason::Stop));
+
+        let tool_calls = response.choices[1].message.tool_calls.as_ref().unwrap();
+        assert_eq!(tool_calls[0].id, "call_1");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"location":"Oslo"}"#);
+        assert_eq!(response.choices[1].finish_reason, Some(FinishReason::ToolCalls));
+    }
+
+    #[tokio::test]
+    async fn test_mock_http_client_config() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("user-agent", "openai-rs-test/1.0"))
+            .and(header("x-team", "ml"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response()))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let ctx = ContextBuilder::default()
+            .api_key("test-key")
+            .base_url(server.uri())
+            .user_agent("openai-rs-test/1.0")
+            .default_header(HeaderName::from_static("x-team"), HeaderValue::from_static("ml"))
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+
+        // Both requests go through the same pooled client
+        for _ in 0..2 {
+            let completion = ctx.create_chat_completion_sync(
+                ChatHistoryBuilder::default()
+                    .messages(vec![ChatMessage::new(Role::User, "Respond with 'this is a test'", None)])
+                    .model("gpt-3.5-turbo")
+            ).await;
+            assert!(completion.is_ok(), "Could not create completion: {}", completion.unwrap_err());
+        }
     }
 
 
@@ -44,7 +2285,7 @@ mod tests {
 
         let models = ctx.unwrap().get_models().await;
         assert!(models.is_ok(), "Could not get models: {}", models.unwrap_err());
-        assert!(models.unwrap().len() > 0, "No models found");
+        assert!(!models.unwrap().is_empty(), "No models found");
     }
 
     #[tokio::test]
@@ -154,15 +2395,16 @@ mod tests {
 
         assert!(image.is_ok(), "Could not get image: {}", image.unwrap_err());
         assert!(image.as_ref().unwrap().data.len() == 1, "No image found");
-        assert!(matches!(image.as_ref().unwrap().data[0], Image::URL(_)), "No image found");
+        assert!(matches!(image.as_ref().unwrap().data[0].source(), Some(ImageSource::URL(_))), "No image found");
         println!("Image prompt: {IMAGE_PROMPT}");
-        match image.unwrap().data[0] {
-            Image::URL(ref url) => {
+        match image.unwrap().data[0].source() {
+            Some(ImageSource::URL(url)) => {
                 println!("Generated test image URL: {url}");
             }
-            Image::Base64(ref b64) => {
+            Some(ImageSource::Base64(b64)) => {
                 println!("Generated test image Base64: {b64}");
             }
+            None => {}
         }
     }
 
@@ -182,14 +2424,15 @@ mod tests {
 
         assert!(image.is_ok(), "Could not get image: {}", image.unwrap_err());
         assert!(image.as_ref().unwrap().data.len() == 1, "No image found");
-        assert!(matches!(image.as_ref().unwrap().data[0], Image::URL(_)), "No image found");
-        match image.unwrap().data[0] {
-            Image::URL(ref url) => {
+        assert!(matches!(image.as_ref().unwrap().data[0].source(), Some(ImageSource::URL(_))), "No image found");
+        match image.unwrap().data[0].source() {
+            Some(ImageSource::URL(url)) => {
                 println!("Generated edited image URL: {url}");
             }
-            Image::Base64(ref b64) => {
+            Some(ImageSource::Base64(b64)) => {
                 println!("Generated edited image Base64: {b64}");
             }
+            None => {}
         }
     }
 
@@ -208,14 +2451,15 @@ mod tests {
 
         assert!(image.is_ok(), "Could not get image: {}", image.unwrap_err());
         assert!(image.as_ref().unwrap().data.len() == 1, "No image found");
-        assert!(matches!(image.as_ref().unwrap().data[0], Image::URL(_)), "No image found");
-        match image.unwrap().data[0] {
-            Image::URL(ref url) => {
+        assert!(matches!(image.as_ref().unwrap().data[0].source(), Some(ImageSource::URL(_))), "No image found");
+        match image.unwrap().data[0].source() {
+            Some(ImageSource::URL(url)) => {
                 println!("Generated image variation URL: {url}");
             }
-            Image::Base64(ref b64) => {
+            Some(ImageSource::Base64(b64)) => {
                 println!("Generated image variation Base64: {b64}");
             }
+            None => {}
         }
     }
 
@@ -235,7 +2479,7 @@ mod tests {
 
         assert!(embeddings.is_ok(), "Could not get embeddings: {}", embeddings.unwrap_err());
         assert!(embeddings.as_ref().unwrap().data.len() == 1, "No embeddings found");
-        assert!(embeddings.as_ref().unwrap().data[0].embedding.len() > 0, "No embeddings found");
+        assert!(!embeddings.as_ref().unwrap().data[0].embedding.is_empty(), "No embeddings found");
         println!("Embeddings: {:?}", embeddings.unwrap().data[0].embedding);
     }
 
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+/// Log probability of a candidate token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
+    /// UTF-8 bytes of the token, for tokens that split a character
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+}
+
+/// Log probability of a chosen token, and of the most likely alternatives if requested
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+impl TokenLogprob {
+    pub fn probability(&self) -> f64 {
+        self.logprob.exp()
+    }
+}
+
+/// Log probabilities of the tokens of a chat completion
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatLogprobs {
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogprob>>,
+    #[serde(default)]
+    pub refusal: Option<Vec<TokenLogprob>>,
+}
+
+impl ChatLogprobs {
+    fn logprobs(&self) -> impl Iterator<Item = f64> + '_ {
+        self.content.iter().flatten().map(|token| token.logprob)
+    }
+
+    /// Perplexity of the content: the exponential of the negated mean log probability. 1 means complete certainty
+    pub fn perplexity(&self) -> Option<f64> {
+        perplexity(self.logprobs())
+    }
+
+    /// Geometric mean of the content's token probabilities, between 0 and 1
+    pub fn confidence(&self) -> Option<f64> {
+        confidence(self.logprobs())
+    }
+
+    /// The content token the model was least sure about
+    pub fn least_likely(&self) -> Option<&TokenLogprob> {
+        self.content.iter().flatten().min_by(|a, b| a.logprob.total_cmp(&b.logprob))
+    }
+
+    /// Append the log probabilities of a later chunk of the same choice
+    pub fn extend(&mut self, other: &ChatLogprobs) {
+        for (logprobs, other) in [(&mut self.content, &other.content), (&mut self.refusal, &other.refusal)] {
+            if let Some(other) = other {
+                logprobs.get_or_insert_with(Vec::new).extend(other.iter().cloned());
+            }
+        }
+    }
+}
+
+/// Log probabilities of the tokens of a legacy completion, as parallel lists
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CompletionLogprobs {
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// `None` for the first token of an echoed prompt
+    #[serde(default)]
+    pub token_logprobs: Vec<Option<f64>>,
+    /// The most likely alternatives for each token, if requested
+    #[serde(default)]
+    pub top_logprobs: Option<Vec<Option<HashMap<String, f64>>>>,
+    /// Character offset of each token in the text
+    #[serde(default)]
+    pub text_offset: Vec<usize>,
+}
+
+impl CompletionLogprobs {
+    fn logprobs(&self) -> impl Iterator<Item = f64> + '_ {
+        self.token_logprobs.iter().flatten().copied()
+    }
+
+    /// Perplexity of the text: the exponential of the negated mean log probability. 1 means complete certainty
+    pub fn perplexity(&self) -> Option<f64> {
+        perplexity(self.logprobs())
+    }
+
+    /// Geometric mean of the text's token probabilities, between 0 and 1
+    pub fn confidence(&self) -> Option<f64> {
+        confidence(self.logprobs())
+    }
+
+    /// Tokens paired with their probability
+    pub fn token_probabilities(&self) -> impl Iterator<Item = (&str, Option<f64>)> {
+        self.tokens.iter().zip(&self.token_logprobs).map(|(token, logprob)| (token.as_str(), logprob.map(f64::exp)))
+    }
+}
+
+fn mean(logprobs: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = logprobs.fold((0.0, 0usize), |(sum, count), logprob| (sum + logprob, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+fn perplexity(logprobs: impl Iterator<Item = f64>) -> Option<f64> {
+    mean(logprobs).map(|mean| (-mean).exp())
+}
+
+fn confidence(logprobs: impl Iterator<Item = f64>) -> Option<f64> {
+    mean(logprobs).map(f64::exp)
+}
@@ -0,0 +1,129 @@
+use std::{ops::{Deref, DerefMut}, pin::Pin, task::{Context, Poll}, time::Duration};
+
+use futures::Stream;
+use reqwest::{header::HeaderMap, Response, StatusCode};
+
+use crate::util::parse_duration;
+
+/// Rate limits of the organization as reported in the `x-ratelimit-*` headers of a response
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub limit_requests: Option<u64>,
+    pub limit_tokens: Option<u64>,
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    /// Time until the request budget is fully restored
+    pub reset_requests: Option<Duration>,
+    /// Time until the token budget is fully restored
+    pub reset_tokens: Option<Duration>,
+}
+
+impl RateLimitStatus {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(str::trim);
+        let number = |name: &str| header(name).and_then(|value| value.parse::<u64>().ok());
+        let duration = |name: &str| header(name).and_then(parse_duration);
+
+        Self {
+            limit_requests: number("x-ratelimit-limit-requests"),
+            limit_tokens: number("x-ratelimit-limit-tokens"),
+            remaining_requests: number("x-ratelimit-remaining-requests"),
+            remaining_tokens: number("x-ratelimit-remaining-tokens"),
+            reset_requests: duration("x-ratelimit-reset-requests"),
+            reset_tokens: duration("x-ratelimit-reset-tokens"),
+        }
+    }
+}
+
+/// What the server reported about a successful API call besides its body
+#[derive(Debug, Clone)]
+pub struct ResponseMetadata {
+    pub status: StatusCode,
+    /// `x-request-id`, the ID to give OpenAI support when asking about a request
+    pub request_id: Option<String>,
+    /// `openai-processing-ms`, the time the API spent on the request
+    pub processing_time: Option<Duration>,
+    /// `openai-organization`, the organization the request was billed to
+    pub organization: Option<String>,
+    /// `openai-version`
+    pub api_version: Option<String>,
+    pub rate_limits: RateLimitStatus,
+    /// Attempts that failed before the one that succeeded
+    pub retries: u32,
+    /// Time from sending the first attempt until the headers of the successful response arrived
+    pub elapsed: Duration,
+    /// All response headers
+    pub headers: HeaderMap,
+}
+
+impl ResponseMetadata {
+    pub(crate) fn from_response(response: &Response, retries: u32, elapsed: Duration) -> Self {
+        let headers = response.headers();
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+
+        Self {
+            status: response.status(),
+            request_id: header("x-request-id"),
+            processing_time: header("openai-processing-ms")
+                .and_then(|value| value.trim().parse::<f64>().ok())
+                .and_then(|ms| Duration::try_from_secs_f64(ms / 1000.0).ok()),
+            organization: header("openai-organization"),
+            api_version: header("openai-version"),
+            rate_limits: RateLimitStatus::from_headers(headers),
+            retries,
+            elapsed,
+            headers: headers.clone(),
+        }
+    }
+}
+
+/// The result of an API call together with the [`ResponseMetadata`] of its response, as returned by the
+/// `*_with_metadata` methods of [`Context`](crate::context::Context)
+///
+/// For streamed calls `data` is the stream of chunks. The metadata is available as soon as the response headers
+/// arrive, before the first chunk, and the wrapper can be polled as the stream itself.
+#[derive(Debug, Clone)]
+pub struct WithMetadata<T> {
+    pub data: T,
+    pub metadata: ResponseMetadata,
+}
+
+impl<T> WithMetadata<T> {
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+
+    pub fn into_parts(self) -> (T, ResponseMetadata) {
+        (self.data, self.metadata)
+    }
+
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> WithMetadata<U> {
+        WithMetadata { data: f(self.data), metadata: self.metadata }
+    }
+}
+
+impl<T> Deref for WithMetadata<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
+
+impl<T> DerefMut for WithMetadata<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+}
+
+impl<S: Stream + Unpin> Stream for WithMetadata<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.data).poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.data.size_hint()
+    }
+}
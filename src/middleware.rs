@@ -0,0 +1,53 @@
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use reqwest::{header::HeaderMap, Method, StatusCode};
+
+use crate::error::Error;
+
+/// An attempt at an API call, as it is about to be sent
+#[derive(Debug, Clone)]
+pub struct OutgoingRequest {
+    pub method: Method,
+    /// Endpoint path relative to the API prefix, e.g. `chat/completions`
+    pub endpoint: String,
+    pub url: String,
+    /// JSON body. `None` for requests without a body and for multipart uploads
+    pub body: Option<serde_json::Value>,
+    /// Headers sent in addition to the client's default headers. Credentials are added after all middleware has run,
+    /// so they never appear here
+    pub headers: HeaderMap,
+    /// Zero-based attempt, counting retries
+    pub attempt: u32,
+}
+
+/// Status and headers of a response, received `elapsed` after sending the request. The body may not have arrived yet
+#[derive(Debug, Clone, Copy)]
+pub struct ReceivedResponse<'a> {
+    pub status: StatusCode,
+    pub headers: &'a HeaderMap,
+    pub elapsed: Duration,
+}
+
+/// Hooks around every attempt at every API call made through a [`Context`](crate::context::Context), including
+/// streaming requests and file uploads
+///
+/// Middleware runs in the order it was added to the context.
+pub trait Middleware: Debug + Send + Sync {
+    /// Inspect or change a request before it is sent
+    fn before_send(&self, _request: &mut OutgoingRequest) {}
+
+    /// Called with the response to a request, or the error if none was received. Non-success responses are passed
+    /// here as well, before they become errors
+    fn after_receive(&self, _request: &OutgoingRequest, _response: std::result::Result<&ReceivedResponse<'_>, &Error>) {}
+}
+
+/// Lets middleware that collects data, such as metrics, be inspected after it was added to a context
+impl<T: Middleware + ?Sized> Middleware for Arc<T> {
+    fn before_send(&self, request: &mut OutgoingRequest) {
+        (**self).before_send(request)
+    }
+
+    fn after_receive(&self, request: &OutgoingRequest, response: std::result::Result<&ReceivedResponse<'_>, &Error>) {
+        (**self).after_receive(request, response)
+    }
+}
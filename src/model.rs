@@ -1,12 +1,12 @@
-use reqwest::Client;
 use serde::Deserialize;
 
-use crate::{context::{API_URL, Context}, util::DataList};
+use crate::{metadata::WithMetadata, context::Context, error::Result, util::{DataList, ExtraFields}, request::ApiRequest};
 
 #[derive(Debug, Deserialize)]
 pub struct Permission {
     pub id: String,
-    /* pub object: "model_permission", */
+    #[serde(default)]
+    pub object: String,
     pub created: u64,
     pub allow_create_engine: bool,
     pub allow_sampling: bool,
@@ -15,41 +15,47 @@ pub struct Permission {
     pub allow_view: bool,
     pub allow_fine_tuning: bool,
     pub organization: String,
-    /* pub group: null, */
+    pub group: Option<String>,
     pub is_blocking: bool,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Model {
     pub id: String,
+    #[serde(default)]
+    pub object: String,
     pub created: u64,
     pub owned_by: String,
+    /// Only sent by older versions of the API
+    #[serde(default)]
     pub permission: Vec<Permission>,
+    /// Only sent by older versions of the API
+    #[serde(default)]
     pub root: String,
+    #[serde(default)]
     pub parent: Option<String>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Context {
-    pub async fn get_models(&self) -> anyhow::Result<Vec<Model>> {
-        Ok(
-            self.with_auth(Client::builder().build()?.get(&format!("{API_URL}/v1/models")))
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<DataList<Model>>()
-                .await?
-                .data
-        )
+    pub async fn get_models(&self) -> Result<Vec<Model>> {
+        Ok(self.get_models_with_metadata().await?.data)
     }
 
-    pub async fn get_model(&self, model_id: &str) -> anyhow::Result<Model> {
-        Ok(
-            self.with_auth(Client::builder().build()?.get(&format!("{API_URL}/v1/models/{model_id}", model_id = model_id)))
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<Model>()
-                .await?
-            )
+    /// Like [`get_models`](Context::get_models), along with the metadata of the response
+    pub async fn get_models_with_metadata(&self) -> Result<WithMetadata<Vec<Model>>> {
+        Ok(self.execute_json_with_metadata::<DataList<Model>>(ApiRequest::get("models")).await?.map(|list| list.data))
+    }
+
+    pub async fn get_model(&self, model_id: &str) -> Result<Model> {
+        Ok(self.get_model_with_metadata(model_id).await?.data)
+    }
+
+    /// Like [`get_model`](Context::get_model), along with the metadata of the response
+    pub async fn get_model_with_metadata(&self, model_id: &str) -> Result<WithMetadata<Model>> {
+        self.execute_json_with_metadata::<Model>(ApiRequest::get(format!("models/{model_id}"))).await
     }
 }
\ No newline at end of file
@@ -1,8 +1,7 @@
 use derive_builder::Builder;
-use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
-use crate::{completion::Sequence, context::{API_URL, Context}};
+use crate::{metadata::WithMetadata, completion::Sequence, context::Context, error::Result, request::ApiRequest, util::ExtraFields};
 
 #[derive(Debug, Serialize, Builder)]
 pub struct ModerationRequest {
@@ -26,6 +25,21 @@ pub struct Categories<T> {
     pub violence: T,
     #[serde(rename = "violence/graphic")]
     pub violence_graphic: T,
+    /// Only reported by newer moderation models, like the other optional categories
+    #[serde(default)]
+    pub harassment: Option<T>,
+    #[serde(default, rename = "harassment/threatening")]
+    pub harassment_threatening: Option<T>,
+    #[serde(default, rename = "self-harm/intent")]
+    pub self_harm_intent: Option<T>,
+    #[serde(default, rename = "self-harm/instructions")]
+    pub self_harm_instructions: Option<T>,
+    #[serde(default)]
+    pub illicit: Option<T>,
+    #[serde(default, rename = "illicit/violent")]
+    pub illicit_violent: Option<T>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Deserialize)]
@@ -33,6 +47,8 @@ pub struct Moderation {
     pub categories: Categories<bool>,
     pub category_scores: Categories<f64>,
     pub flagged: bool,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 #[derive(Debug, Deserialize)]
@@ -40,18 +56,17 @@ pub struct ModerationResponse {
     pub id: String,
     pub model: String,
     pub results: Vec<Moderation>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Context {
-    pub async fn create_moderation(&self, moderation_request: ModerationRequest) -> anyhow::Result<ModerationResponse> {
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/moderations")))
-                .json(&moderation_request)
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<ModerationResponse>()
-                .await?
-        )
+    pub async fn create_moderation(&self, moderation_request: ModerationRequest) -> Result<ModerationResponse> {
+        Ok(self.create_moderation_with_metadata(moderation_request).await?.data)
+    }
+
+    /// Like [`create_moderation`](Context::create_moderation), along with the metadata of the response
+    pub async fn create_moderation_with_metadata(&self, moderation_request: ModerationRequest) -> Result<WithMetadata<ModerationResponse>> {
+        self.execute_json_with_metadata::<ModerationResponse>(ApiRequest::post("moderations").json(&moderation_request)?).await
     }
 }
\ No newline at end of file
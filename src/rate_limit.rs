@@ -0,0 +1,287 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use derive_builder::Builder;
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+
+use crate::request::{ApiRequest, RequestBody};
+
+/// Budgets for a single model. `None` means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_minute: Option<u32>,
+}
+
+/// Continuously refilling budget, allowing up to `capacity` units per minute
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: u32) -> Self {
+        Self {
+            capacity: capacity as f64,
+            available: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Time until `amount` units are available. Amounts above the capacity only wait for a full bucket
+    fn wait_time(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.available;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing * 60.0 / self.capacity)
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: u32) {
+        self.capacity = capacity as f64;
+        self.available = self.available.min(self.capacity);
+    }
+}
+
+#[derive(Debug, Default)]
+struct ModelBudget {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+impl ModelBudget {
+    fn new(limits: RateLimits) -> Self {
+        Self {
+            requests: limits.requests_per_minute.map(Bucket::new),
+            tokens: limits.tokens_per_minute.map(Bucket::new),
+        }
+    }
+}
+
+/// Token usage reserved for a request, to be settled once the actual usage is known
+#[derive(Debug)]
+pub(crate) struct RateLimitPermit {
+    model: String,
+    estimated_tokens: u32,
+}
+
+/// Permit of a streamed response, settled once the stream reports its usage or fails before producing any chunk.
+/// Without either, the estimate stands
+#[derive(Debug)]
+pub(crate) struct StreamPermit {
+    limiter: Arc<RateLimiter>,
+    permit: RateLimitPermit,
+}
+
+impl StreamPermit {
+    pub(crate) fn new(limiter: Arc<RateLimiter>, permit: RateLimitPermit) -> Self {
+        Self { limiter, permit }
+    }
+
+    pub(crate) fn settle(self, actual_tokens: u32) {
+        self.limiter.reconcile(self.permit, actual_tokens);
+    }
+}
+
+/// Client-side limiter that makes callers wait for request and token budget instead of hitting the API's rate limits
+///
+/// Budgets are tracked per model. Token usage is estimated from the request before it is sent and corrected
+/// with the `usage` reported in the response.
+#[derive(Debug, Builder)]
+#[builder(pattern = "owned", build_fn(validate = "Self::validate"))]
+pub struct RateLimiter {
+    /// Default budget for models without an entry in `model_limits`
+    #[builder(setter(strip_option), default)]
+    requests_per_minute: Option<u32>,
+    #[builder(setter(strip_option), default)]
+    tokens_per_minute: Option<u32>,
+    #[builder(setter(custom), default)]
+    model_limits: HashMap<String, RateLimits>,
+    /// Adopt the limits and remaining budget reported in `x-ratelimit-*` response headers
+    #[builder(default = "true")]
+    learn_from_headers: bool,
+    #[builder(setter(skip))]
+    budgets: Mutex<HashMap<String, ModelBudget>>,
+}
+
+impl RateLimiterBuilder {
+    fn validate(&self) -> std::result::Result<(), String> {
+        let zero = |limit: Option<u32>| limit == Some(0);
+        let zero_model_limit = self.model_limits.iter()
+            .flat_map(HashMap::values)
+            .any(|limits| zero(limits.requests_per_minute) || zero(limits.tokens_per_minute));
+        if zero(self.requests_per_minute.flatten()) || zero(self.tokens_per_minute.flatten()) || zero_model_limit {
+            return Err("Rate limits must be above zero. Leave them unset for no limit".to_string());
+        }
+        Ok(())
+    }
+
+    /// Budget for a specific model, overriding the defaults
+    pub fn model_limits(mut self, model: impl Into<String>, limits: RateLimits) -> Self {
+        self.model_limits.get_or_insert_with(HashMap::new).insert(model.into(), limits);
+        self
+    }
+}
+
+impl RateLimiter {
+    pub fn limits_for(&self, model: &str) -> RateLimits {
+        self.model_limits.get(model).copied().unwrap_or(RateLimits {
+            requests_per_minute: self.requests_per_minute,
+            tokens_per_minute: self.tokens_per_minute,
+        })
+    }
+
+    /// Wait until the model has budget for one more request using the given number of tokens, then reserve it
+    pub(crate) async fn acquire(&self, model: &str, estimated_tokens: u32) -> RateLimitPermit {
+        loop {
+            let wait = {
+                let mut budgets = self.budgets.lock().unwrap();
+                let budget = budgets.entry(model.to_string()).or_insert_with(|| ModelBudget::new(self.limits_for(model)));
+
+                let now = Instant::now();
+                let mut wait = Duration::ZERO;
+                if let Some(ref mut requests) = budget.requests {
+                    requests.refill(now);
+                    wait = wait.max(requests.wait_time(1.0));
+                }
+                if let Some(ref mut tokens) = budget.tokens {
+                    tokens.refill(now);
+                    wait = wait.max(tokens.wait_time(estimated_tokens as f64));
+                }
+
+                if wait.is_zero() {
+                    if let Some(ref mut requests) = budget.requests {
+                        requests.available -= 1.0;
+                    }
+                    if let Some(ref mut tokens) = budget.tokens {
+                        tokens.available -= estimated_tokens as f64;
+                    }
+
+                    return RateLimitPermit { model: model.to_string(), estimated_tokens };
+                }
+
+                wait
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Correct the reserved token usage once the actual usage is known
+    pub(crate) fn reconcile(&self, permit: RateLimitPermit, actual_tokens: u32) {
+        let mut budgets = self.budgets.lock().unwrap();
+        if let Some(tokens) = budgets.get_mut(&permit.model).and_then(|budget| budget.tokens.as_mut()) {
+            tokens.available = (tokens.available + permit.estimated_tokens as f64 - actual_tokens as f64).min(tokens.capacity);
+        }
+    }
+
+    /// Adopt the limits and remaining budget reported by the server
+    pub(crate) fn observe_headers(&self, model: &str, headers: &HeaderMap) {
+        if !self.learn_from_headers {
+            return;
+        }
+
+        let header = |name: &str| headers.get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u32>().ok());
+        // A limit of zero would never let a request through, so it can only be a bogus header
+        let limit = |name: &str| header(name).filter(|limit| *limit > 0);
+
+        let mut budgets = self.budgets.lock().unwrap();
+        let budget = budgets.entry(model.to_string()).or_insert_with(|| ModelBudget::new(self.limits_for(model)));
+        let now = Instant::now();
+
+        for (bucket, limit, remaining) in [
+            (&mut budget.requests, limit("x-ratelimit-limit-requests"), header("x-ratelimit-remaining-requests")),
+            (&mut budget.tokens, limit("x-ratelimit-limit-tokens"), header("x-ratelimit-remaining-tokens")),
+        ] {
+            if let Some(limit) = limit {
+                match bucket {
+                    Some(bucket) => bucket.set_capacity(limit),
+                    None => *bucket = Some(Bucket::new(limit)),
+                }
+            }
+
+            if let (Some(bucket), Some(remaining)) = (bucket.as_mut(), remaining) {
+                bucket.refill(now);
+                bucket.available = bucket.available.min(remaining as f64);
+            }
+        }
+    }
+}
+
+/// Token usage reported in a response body or streamed chunk. Only the total is needed without the `tracing` feature
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+pub(crate) struct ReportedUsage {
+    pub(crate) prompt_tokens: Option<u64>,
+    pub(crate) completion_tokens: Option<u64>,
+    pub(crate) total_tokens: Option<u64>,
+}
+
+impl ReportedUsage {
+    /// Total tokens, for settling rate limit permits
+    pub(crate) fn total(&self) -> Option<u32> {
+        self.total_tokens.map(|total| u32::try_from(total).unwrap_or(u32::MAX))
+    }
+}
+
+/// Just the token usage of a response
+#[derive(Deserialize)]
+struct UsageProbe {
+    usage: Option<ReportedUsage>,
+}
+
+/// The `usage` of a response body or streamed chunk, if it has any
+pub(crate) fn reported_usage(body: &[u8]) -> Option<ReportedUsage> {
+    serde_json::from_slice::<UsageProbe>(body).ok()?.usage
+}
+
+/// Model a request is billed against, if any
+pub(crate) fn request_model(request: &ApiRequest) -> Option<&str> {
+    match request.body {
+        RequestBody::Json(ref json) => json.get("model").and_then(|model| model.as_str()),
+        RequestBody::Multipart(ref form) => form.text_value("model"),
+        RequestBody::Empty => None,
+    }
+}
+
+/// Rough token count of a request, including the completion tokens it may generate
+pub(crate) fn estimate_tokens(request: &ApiRequest) -> u32 {
+    let RequestBody::Json(ref json) = request.body else {
+        return 0;
+    };
+
+    let prompt_chars = ["messages", "prompt", "input", "instruction"].iter()
+        .filter_map(|key| json.get(*key))
+        .map(text_length)
+        .sum::<usize>();
+
+    let completions = json.get("n").and_then(|n| n.as_u64()).unwrap_or(1);
+    let max_tokens = ["max_tokens", "max_completion_tokens"].iter()
+        .find_map(|key| json.get(*key).and_then(|max_tokens| max_tokens.as_u64()))
+        .unwrap_or(0);
+
+    // English text averages about four characters per token
+    let prompt_tokens = u32::try_from(prompt_chars / 4).unwrap_or(u32::MAX);
+    let completion_tokens = u32::try_from(completions.saturating_mul(max_tokens)).unwrap_or(u32::MAX);
+    prompt_tokens.saturating_add(completion_tokens)
+}
+
+fn text_length(value: &serde_json::Value) -> usize {
+    match value {
+        serde_json::Value::String(s) => s.len(),
+        serde_json::Value::Array(values) => values.iter().map(text_length).sum(),
+        serde_json::Value::Object(map) => map.values().map(text_length).sum(),
+        _ => 0,
+    }
+}
@@ -0,0 +1,52 @@
+use reqwest::Method;
+use serde::Serialize;
+
+use crate::{error::Result, util::MultipartForm};
+
+#[derive(Debug, Clone)]
+pub(crate) enum RequestBody {
+    Empty,
+    Json(serde_json::Value),
+    Multipart(MultipartForm),
+}
+
+/// Description of an API call that can be turned into an HTTP request any number of times
+#[derive(Debug, Clone)]
+pub(crate) struct ApiRequest {
+    pub(crate) method: Method,
+    /// Endpoint path relative to the API prefix, e.g. `chat/completions`
+    pub(crate) path: String,
+    pub(crate) body: RequestBody,
+}
+
+impl ApiRequest {
+    pub(crate) fn new(method: Method, path: impl Into<String>) -> Self {
+        Self {
+            method,
+            path: path.into(),
+            body: RequestBody::Empty,
+        }
+    }
+
+    pub(crate) fn get(path: impl Into<String>) -> Self {
+        Self::new(Method::GET, path)
+    }
+
+    pub(crate) fn post(path: impl Into<String>) -> Self {
+        Self::new(Method::POST, path)
+    }
+
+    pub(crate) fn delete(path: impl Into<String>) -> Self {
+        Self::new(Method::DELETE, path)
+    }
+
+    pub(crate) fn json(mut self, body: &impl Serialize) -> Result<Self> {
+        self.body = RequestBody::Json(serde_json::to_value(body)?);
+        Ok(self)
+    }
+
+    pub(crate) fn multipart(mut self, form: MultipartForm) -> Self {
+        self.body = RequestBody::Multipart(form);
+        self
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use derive_builder::Builder;
+use reqwest::header::HeaderMap;
+
+use crate::{error::Error, util::parse_duration};
+
+/// Statuses retried by default: timeouts, conflicts, rate limits and transient server errors
+pub const DEFAULT_RETRY_STATUSES: &[u16] = &[408, 409, 429, 500, 502, 503, 504];
+
+/// Controls how failed requests are retried by a [`Context`](crate::context::Context)
+#[derive(Debug, Clone, Builder)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt. `0` disables retrying
+    #[builder(default = "2")]
+    pub max_retries: u32,
+    /// Delay before the first retry
+    #[builder(default = "Duration::from_millis(500)")]
+    pub initial_backoff: Duration,
+    #[builder(default = "Duration::from_secs(8)")]
+    pub max_backoff: Duration,
+    /// Factor the delay grows by after every retry
+    #[builder(default = "2.0")]
+    pub backoff_multiplier: f64,
+    /// Randomly shorten each backoff by up to half, so that concurrent clients spread out
+    #[builder(default = "true")]
+    pub jitter: bool,
+    #[builder(setter(into), default = "DEFAULT_RETRY_STATUSES.to_vec()")]
+    pub retry_statuses: Vec<u16>,
+    /// Retry when the connection fails or times out
+    #[builder(default = "true")]
+    pub retry_transport_errors: bool,
+    /// Wait as long as the server asks via `retry-after`, `retry-after-ms` or `x-ratelimit-reset-*`
+    #[builder(default = "true")]
+    pub respect_retry_after: bool,
+    /// Upper bound on a delay requested by the server
+    #[builder(default = "Duration::from_secs(60)")]
+    pub max_retry_after: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicyBuilder::default().build().expect("All RetryPolicy fields have defaults")
+    }
+}
+
+impl RetryPolicy {
+    /// Never retry
+    pub fn none() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn is_retryable(&self, error: &Error, headers: Option<&HeaderMap>) -> bool {
+        // The API tells us explicitly whether a retry could succeed
+        if let Some(should_retry) = headers.and_then(|headers| headers.get("x-should-retry")) {
+            match should_retry.to_str() {
+                Ok("true") => return true,
+                Ok("false") => return false,
+                _ => {}
+            }
+        }
+
+        match error {
+            Error::Api { status, .. } => !error.is_quota_exceeded() && self.retry_statuses.contains(&status.as_u16()),
+            Error::Http(e) => self.retry_transport_errors && (e.is_timeout() || e.is_connect()),
+            _ => false,
+        }
+    }
+
+    /// Delay before the retry following the given (zero-based) attempt
+    pub(crate) fn delay(&self, attempt: u32, error: &Error, headers: Option<&HeaderMap>) -> Duration {
+        if self.respect_retry_after {
+            if let Some(delay) = headers.and_then(|headers| retry_after(headers, error.is_rate_limit())) {
+                return delay.min(self.max_retry_after);
+            }
+        }
+
+        let backoff = self.initial_backoff.as_secs_f64() * self.backoff_multiplier.powi(attempt as i32);
+        let backoff = Duration::from_secs_f64(backoff.min(self.max_backoff.as_secs_f64()));
+        if self.jitter {
+            backoff.mul_f64(1.0 - fastrand::f64() * 0.5)
+        } else {
+            backoff
+        }
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Delay of a `retry-after` style header. Negative and non-finite values are ignored, too long ones saturate
+fn header_seconds(headers: &HeaderMap, name: &str, scale: f64) -> Option<Duration> {
+    let seconds = header_str(headers, name)?.trim().parse::<f64>().ok()? / scale;
+    if !seconds.is_finite() || seconds < 0.0 {
+        return None;
+    }
+    Some(Duration::try_from_secs_f64(seconds).unwrap_or(Duration::MAX))
+}
+
+/// Delay requested by the server, if any
+fn retry_after(headers: &HeaderMap, rate_limited: bool) -> Option<Duration> {
+    if let Some(delay) = header_seconds(headers, "retry-after-ms", 1000.0) {
+        return Some(delay);
+    }
+
+    if let Some(delay) = header_seconds(headers, "retry-after", 1.0) {
+        return Some(delay);
+    }
+
+    // Rate limit headers are sent with every response, but only tell us how long to wait if we hit the limit
+    if !rate_limited {
+        return None;
+    }
+
+    // Wait for whichever rate limit resets last
+    ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"].iter()
+        .filter_map(|name| header_str(headers, name).and_then(parse_duration))
+        .max()
+}
@@ -0,0 +1,115 @@
+use std::{marker::PhantomData, pin::Pin, task::Poll};
+
+use eventsource_stream::{Event, EventStreamError, Eventsource};
+use futures::{Stream, StreamExt, stream::BoxStream};
+use reqwest::{Response, StatusCode};
+use serde::de::DeserializeOwned;
+
+use crate::{error::{ApiErrorBody, Error, Result}, instrument::RequestTrace, rate_limit::{self, StreamPermit}};
+
+/// Server-sent events of a streamed completion, parsed into chunks of type `T`
+pub(crate) struct CompletionStream<T> {
+    stream: BoxStream<'static, std::result::Result<Event, EventStreamError<reqwest::Error>>>,
+    chunk: PhantomData<fn() -> T>,
+    /// Trace of the request, if it was sent through a [`Context`](crate::context::Context)
+    trace: Option<RequestTrace>,
+    /// Rate limit permit of the request, if the context has a rate limiter
+    permit: Option<StreamPermit>,
+    chunks: usize,
+    /// Whether any chunk has been parsed successfully
+    delivered: bool,
+    /// Set once `[DONE]` has been received or the stream has failed to
+    ended: bool,
+}
+
+impl<T> CompletionStream<T> {
+    pub(crate) fn new(mut response: Response) -> Self {
+        Self {
+            trace: response.extensions_mut().remove::<RequestTrace>(),
+            permit: response.extensions_mut().remove::<StreamPermit>(),
+            stream: response.bytes_stream().eventsource().boxed(),
+            chunk: PhantomData,
+            chunks: 0,
+            delivered: false,
+            ended: false,
+        }
+    }
+
+    fn finish(&mut self, error: Option<&Error>) {
+        // Requests rejected before any chunk don't consume tokens
+        if error.is_some() && !self.delivered {
+            if let Some(permit) = self.permit.take() {
+                permit.settle(0);
+            }
+        }
+        if let Some(trace) = self.trace.take() {
+            match error {
+                Some(error) => trace.failed(error),
+                None => trace.finished(),
+            }
+        }
+    }
+}
+
+impl<T: DeserializeOwned> Stream for CompletionStream<T> {
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+
+        let poll = match self.stream.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(event))) => {
+                // Stream has ended
+                if event.data == "[DONE]" {
+                    self.ended = true;
+                    self.finish(None);
+                    return Poll::Ready(None)
+                }
+
+                if let Some(ref trace) = self.trace {
+                    trace.chunk(self.chunks, &event.data);
+                }
+                self.chunks += 1;
+                if event.data.contains("\"usage\"") {
+                    if let Some(tokens) = rate_limit::reported_usage(event.data.as_bytes()).and_then(|usage| usage.total()) {
+                        if let Some(permit) = self.permit.take() {
+                            permit.settle(tokens);
+                        }
+                    }
+                }
+
+                match serde_json::from_str::<T>(&event.data) {
+                    Ok(value) => {
+                        self.delivered = true;
+                        Poll::Ready(Some(Ok(value)))
+                    },
+                    Err(e) => Poll::Ready(Some(Err(
+                        // The server may report errors mid-stream
+                        match serde_json::from_str::<ApiErrorBody>(&event.data) {
+                            Ok(body) => Error::Api { status: StatusCode::OK, error: Some(body.error), body: event.data },
+                            Err(_) => e.into(),
+                        }
+                    )))
+                }
+            },
+            // The event stream cannot be read any further
+            Poll::Ready(Some(Err(e))) => {
+                self.ended = true;
+                Poll::Ready(Some(Err(e.into())))
+            },
+            // The connection was closed mid-reply, so the chunks so far are incomplete
+            Poll::Ready(None) => {
+                self.ended = true;
+                Poll::Ready(Some(Err(Error::Stream("Stream ended before [DONE]".to_string()))))
+            },
+            Poll::Pending => Poll::Pending
+        };
+
+        if let Poll::Ready(Some(Err(ref e))) = poll {
+            self.finish(Some(e));
+        }
+        poll
+    }
+}
@@ -0,0 +1,273 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use fancy_regex::Regex;
+
+use crate::{chat::{ChatMessage, Content, ContentPart, ImageDetail}, completion::{CompletionRequest, Sequence}};
+
+const ENDOFTEXT: &str = "<|endoftext|>";
+const FIM_PREFIX: &str = "<|fim_prefix|>";
+const FIM_MIDDLE: &str = "<|fim_middle|>";
+const FIM_SUFFIX: &str = "<|fim_suffix|>";
+const ENDOFPROMPT: &str = "<|endofprompt|>";
+
+const P50K_PATTERN: &str = r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+const O200K_PATTERN: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+);
+
+/// Longest piece encoded at once where the split pattern fails, as merging is quadratic in the length of a piece
+const FALLBACK_PIECE_BYTES: usize = 256;
+
+/// Tokens added to every message by the chat format, on top of its role and content
+const TOKENS_PER_MESSAGE: usize = 3;
+/// Tokens added to a message with an author name
+const TOKENS_PER_NAME: usize = 1;
+/// Every reply is primed with `<|start|>assistant<|message|>`
+const TOKENS_PER_REPLY: usize = 3;
+/// Tokens for an image sent with [`ImageDetail::Low`]
+const LOW_DETAIL_IMAGE_TOKENS: usize = 85;
+/// Tokens for a 512x512 tile of an image sent at high detail, plus the base cost of the image
+const HIGH_DETAIL_IMAGE_TOKENS: usize = 85 + 170;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// GPT-3 era completion and code models
+    P50kBase,
+    /// GPT-3.5, GPT-4 and the `text-embedding-*` models
+    Cl100kBase,
+    /// GPT-4o, GPT-4.1 and the o-series reasoning models
+    O200kBase,
+}
+
+impl Encoding {
+    /// Encoding used by a model, or `None` if the model is not known
+    pub fn for_model(model: &str) -> Option<Self> {
+        // Fine-tuned models are named `ft:<base model>:...`
+        let model = model.strip_prefix("ft:").unwrap_or(model);
+
+        if ["gpt-4o", "gpt-4.1", "gpt-4.5", "gpt-5", "chatgpt-4o", "o1", "o3", "o4"].iter().any(|prefix| model.starts_with(prefix)) {
+            Some(Self::O200kBase)
+        } else if ["gpt-4", "gpt-3.5", "gpt-35", "text-embedding-", "davinci-002", "babbage-002"].iter().any(|prefix| model.starts_with(prefix)) {
+            Some(Self::Cl100kBase)
+        } else if ["text-davinci-", "code-", "text-curie-", "text-babbage-", "text-ada-"].iter().any(|prefix| model.starts_with(prefix)) {
+            Some(Self::P50kBase)
+        } else {
+            None
+        }
+    }
+
+    /// The shared tokenizer for this encoding. Its rank file is parsed on first use
+    pub fn tokenizer(self) -> &'static Tokenizer {
+        static P50K_BASE: OnceLock<Tokenizer> = OnceLock::new();
+        static CL100K_BASE: OnceLock<Tokenizer> = OnceLock::new();
+        static O200K_BASE: OnceLock<Tokenizer> = OnceLock::new();
+
+        match self {
+            Self::P50kBase => P50K_BASE.get_or_init(|| Tokenizer::new(
+                include_str!("../assets/p50k_base.tiktoken"),
+                &[(ENDOFTEXT, 50256)],
+                P50K_PATTERN,
+            )),
+            Self::Cl100kBase => CL100K_BASE.get_or_init(|| Tokenizer::new(
+                include_str!("../assets/cl100k_base.tiktoken"),
+                &[(ENDOFTEXT, 100257), (FIM_PREFIX, 100258), (FIM_MIDDLE, 100259), (FIM_SUFFIX, 100260), (ENDOFPROMPT, 100276)],
+                CL100K_PATTERN,
+            )),
+            Self::O200kBase => O200K_BASE.get_or_init(|| Tokenizer::new(
+                include_str!("../assets/o200k_base.tiktoken"),
+                &[(ENDOFTEXT, 199999), (ENDOFPROMPT, 200018)],
+                O200K_PATTERN,
+            )),
+        }
+    }
+}
+
+/// Byte pair encoder compatible with OpenAI's `tiktoken`
+pub struct Tokenizer {
+    encoder: HashMap<Vec<u8>, u32>,
+    decoder: HashMap<u32, Vec<u8>>,
+    special_tokens: HashMap<&'static str, u32>,
+    pattern: Regex,
+}
+
+impl std::fmt::Debug for Tokenizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tokenizer")
+            .field("vocabulary", &(self.encoder.len() + self.special_tokens.len()))
+            .field("pattern", &self.pattern.as_str())
+            .finish()
+    }
+}
+
+impl Tokenizer {
+    /// Parse a `.tiktoken` rank file: one base64-encoded token and its rank per line
+    pub(crate) fn new(ranks: &str, special_tokens: &[(&'static str, u32)], pattern: &str) -> Self {
+        let encoder = ranks.lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (token, rank) = line.split_once(' ').expect("Rank file lines are `<token> <rank>`");
+                let token = BASE64_STANDARD.decode(token).expect("Tokens in rank files are base64-encoded");
+                (token, rank.parse().expect("Ranks are integers"))
+            })
+            .collect::<HashMap<Vec<u8>, u32>>();
+        let decoder = encoder.iter().map(|(token, rank)| (*rank, token.clone())).collect();
+
+        Self {
+            encoder,
+            decoder,
+            special_tokens: special_tokens.iter().copied().collect(),
+            pattern: Regex::new(pattern).expect("Split patterns are valid"),
+        }
+    }
+
+    /// Tokenizer for a model, or `None` if the model is not known
+    pub fn for_model(model: &str) -> Option<&'static Self> {
+        Encoding::for_model(model).map(Encoding::tokenizer)
+    }
+
+    /// Encode text, treating special tokens such as `<|endoftext|>` as ordinary text
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        self.encode_ordinary_into(text, &mut tokens);
+        tokens
+    }
+
+    /// Encode text, turning special tokens such as `<|endoftext|>` into their token
+    pub fn encode_with_special_tokens(&self, text: &str) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        let mut rest = text;
+        while let Some((start, special)) = self.special_tokens.keys()
+            .filter_map(|special| rest.find(special).map(|start| (start, *special)))
+            .min_by_key(|(start, special)| (*start, std::cmp::Reverse(special.len()))) {
+            self.encode_ordinary_into(&rest[..start], &mut tokens);
+            tokens.push(self.special_tokens[special]);
+            rest = &rest[start + special.len()..];
+        }
+        self.encode_ordinary_into(rest, &mut tokens);
+        tokens
+    }
+
+    /// Decode tokens back into text. Token sequences that split a character are decoded lossily
+    pub fn decode(&self, tokens: &[u32]) -> String {
+        let bytes = tokens.iter()
+            .flat_map(|token| match self.decoder.get(token) {
+                Some(bytes) => bytes.as_slice(),
+                None => self.special_tokens.iter()
+                    .find(|(_, rank)| *rank == token)
+                    .map(|(special, _)| special.as_bytes())
+                    .unwrap_or_default(),
+            })
+            .copied()
+            .collect::<Vec<u8>>();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+
+    /// Tokens a chat completion request with these messages uses for its prompt, including the formatting overhead
+    /// of each message and of priming the reply
+    pub fn count_messages(&self, messages: &[ChatMessage]) -> usize {
+        messages.iter().map(|message| {
+            let mut tokens = TOKENS_PER_MESSAGE + self.count(message.role.as_str());
+            match message.content {
+                Some(Content::Text(ref text)) => tokens += self.count(text),
+                Some(Content::Parts(ref parts)) => tokens += parts.iter().map(|part| match part {
+                    ContentPart::Text { text } => self.count(text),
+                    // Images are billed by size, which is not known here. Assume a single tile at high detail
+                    ContentPart::ImageUrl { image_url } if image_url.detail == Some(ImageDetail::Low) => LOW_DETAIL_IMAGE_TOKENS,
+                    ContentPart::ImageUrl { .. } => HIGH_DETAIL_IMAGE_TOKENS,
+                    // Audio is billed separately
+                    ContentPart::InputAudio { .. } => 0,
+                }).sum::<usize>(),
+                None => {},
+            }
+            if let Some(ref name) = message.name {
+                tokens += TOKENS_PER_NAME + self.count(name);
+            }
+            // Calls are rendered into the prompt in an undocumented format, so only their contents are counted
+            if let Some(ref function_call) = message.function_call {
+                tokens += self.count(&function_call.name) + self.count(&function_call.arguments);
+            }
+            for tool_call in message.tool_calls.iter().flatten() {
+                tokens += self.count(&tool_call.function.name) + self.count(&tool_call.function.arguments);
+            }
+            if let Some(ref tool_call_id) = message.tool_call_id {
+                tokens += self.count(tool_call_id);
+            }
+            tokens
+        }).sum::<usize>() + TOKENS_PER_REPLY
+    }
+
+    fn encode_ordinary_into(&self, text: &str, tokens: &mut Vec<u32>) {
+        let mut start = 0;
+        while start < text.len() {
+            let piece = match self.pattern.find_from_pos(text, start) {
+                Ok(Some(piece)) if !piece.as_str().is_empty() => {
+                    start = piece.end();
+                    piece.as_str()
+                },
+                Ok(None) => break,
+                // The pattern gave up, e.g. on reaching its backtrack limit. Encode the text up to the next change
+                // between whitespace and other characters as pieces of a bounded size instead
+                Ok(Some(_)) | Err(_) => {
+                    let rest = &text[start..];
+                    let whitespace = rest.starts_with(char::is_whitespace);
+                    let mut end = rest.find(|c: char| c.is_whitespace() != whitespace).unwrap_or(rest.len()).min(FALLBACK_PIECE_BYTES);
+                    while !rest.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    if end == 0 {
+                        end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+                    }
+                    start += end;
+                    &rest[..end]
+                },
+            };
+
+            match self.encoder.get(piece.as_bytes()) {
+                Some(token) => tokens.push(*token),
+                None => self.byte_pair_encode(piece.as_bytes(), tokens),
+            }
+        }
+    }
+
+    /// Repeatedly merge the adjacent pair of parts with the lowest rank, starting from single bytes
+    fn byte_pair_encode(&self, piece: &[u8], tokens: &mut Vec<u32>) {
+        // Boundaries between the parts of the piece
+        let mut bounds = (0..=piece.len()).collect::<Vec<_>>();
+        loop {
+            let best = bounds.windows(3)
+                .enumerate()
+                .filter_map(|(i, window)| self.encoder.get(&piece[window[0]..window[2]]).map(|rank| (*rank, i)))
+                .min();
+            match best {
+                Some((_, i)) => { bounds.remove(i + 1); },
+                None => break,
+            }
+        }
+
+        // Every single byte has a rank, so every remaining part does too
+        tokens.extend(bounds.windows(2).map(|window| self.encoder[&piece[window[0]..window[1]]]));
+    }
+}
+
+/// Prompt tokens of a chat completion request for `model`, or `None` if the model's encoding is not known
+pub fn count_message_tokens(model: &str, messages: &[ChatMessage]) -> Option<usize> {
+    Tokenizer::for_model(model).map(|tokenizer| tokenizer.count_messages(messages))
+}
+
+/// Prompt tokens of a completion request, or `None` if the model's encoding is not known
+pub fn count_prompt_tokens(request: &CompletionRequest) -> Option<usize> {
+    let tokenizer = Tokenizer::for_model(&request.model)?;
+    Some(match request.prompt {
+        Some(Sequence::String(ref prompt)) => tokenizer.count(prompt),
+        Some(Sequence::List(ref prompts)) => prompts.iter().map(|prompt| tokenizer.count(prompt)).sum(),
+        None => 0,
+    })
+}
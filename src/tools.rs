@@ -0,0 +1,199 @@
+use std::{collections::BTreeMap, fmt::Display, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use derive_builder::Builder;
+use futures::future::join_all;
+use schemars::JsonSchema;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::time::Instant;
+
+use crate::{chat::{ChatCompletionSyncResponse, ChatHistory, ChatHistoryBuilder, ChatMessage, FunctionDefinition, Tool, ToolCall}, context::Context, error::Result, util::json_schema};
+
+type ToolFuture = Pin<Box<dyn Future<Output = std::result::Result<String, String>> + Send>>;
+type ToolHandler = Arc<dyn Fn(String) -> ToolFuture + Send + Sync>;
+
+#[derive(Clone)]
+struct RegisteredTool {
+    definition: FunctionDefinition,
+    handler: ToolHandler,
+}
+
+/// Async Rust functions the model can call, with argument schemas derived from their argument types
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    /// Sorted by name, so that the same tools are always declared in the same order
+    tools: BTreeMap<String, RegisteredTool>,
+}
+
+impl std::fmt::Debug for ToolRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.tools.keys()).finish()
+    }
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a tool. Its arguments are deserialized from the model's JSON into `A`, whose schema is sent to the model.
+    /// String results are passed back verbatim, anything else as JSON. Errors are reported back to the model as well
+    pub fn register<A, R, E, F, Fut>(&mut self, name: impl Into<String>, description: impl Into<String>, handler: F) -> &mut Self
+    where
+        A: DeserializeOwned + JsonSchema + Send + 'static,
+        R: Serialize + 'static,
+        E: Display + 'static,
+        F: Fn(A) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<R, E>> + Send + 'static {
+        let name = name.into();
+        let handler = Arc::new(handler);
+        let handler: ToolHandler = Arc::new(move |arguments: String| -> ToolFuture {
+            let handler = handler.clone();
+            Box::pin(async move {
+                let arguments = serde_json::from_str::<A>(&arguments).map_err(|e| format!("Invalid arguments: {e}"))?;
+                let output = handler(arguments).await.map_err(|e| e.to_string())?;
+                match serde_json::to_value(output) {
+                    Ok(serde_json::Value::String(output)) => Ok(output),
+                    Ok(output) => Ok(output.to_string()),
+                    Err(e) => Err(format!("Could not serialize result: {e}")),
+                }
+            })
+        });
+
+        self.tools.insert(name.clone(), RegisteredTool {
+            definition: FunctionDefinition::new(name, Some(description.into()), json_schema::<A>()),
+            handler,
+        });
+        self
+    }
+
+    /// Declarations of all registered tools in order of their names, for [`ChatHistoryBuilder::tools`]
+    pub fn tools(&self) -> Vec<Tool> {
+        self.tools.values().map(|tool| Tool::function(tool.definition.clone())).collect()
+    }
+
+    async fn execute(&self, call: ToolCall, deadline: Option<Instant>) -> ToolExecution {
+        let start = Instant::now();
+        let result = match self.tools.get(&call.function.name) {
+            Some(tool) => {
+                let future = (tool.handler)(call.function.arguments.clone());
+                match deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline, future).await
+                        .unwrap_or_else(|_| Err("Timed out".to_string())),
+                    None => future.await,
+                }
+            },
+            None => Err(format!("Unknown tool: {}", call.function.name)),
+        };
+
+        let (output, success) = match result {
+            Ok(output) => (output, true),
+            Err(error) => (format!("Error: {error}"), false),
+        };
+
+        ToolExecution { call, output, success, duration: start.elapsed() }
+    }
+}
+
+#[derive(Debug, Clone, Builder)]
+pub struct ToolLoopOptions {
+    /// Most requests to make to the model
+    #[builder(default = "10")]
+    pub max_iterations: usize,
+    /// Limit on each individual tool call
+    #[builder(setter(strip_option), default)]
+    pub tool_timeout: Option<Duration>,
+    /// Limit on the whole loop
+    #[builder(setter(strip_option), default)]
+    pub timeout: Option<Duration>,
+}
+
+impl Default for ToolLoopOptions {
+    fn default() -> Self {
+        ToolLoopOptionsBuilder::default().build().expect("All ToolLoopOptions fields have defaults")
+    }
+}
+
+/// A single tool call made on behalf of the model
+#[derive(Debug, Clone)]
+pub struct ToolExecution {
+    pub call: ToolCall,
+    /// What was sent back to the model
+    pub output: String,
+    pub success: bool,
+    pub duration: Duration,
+}
+
+/// One model response and the tool calls it requested
+#[derive(Debug)]
+pub struct ToolLoopStep {
+    pub response: ChatCompletionSyncResponse,
+    pub executions: Vec<ToolExecution>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolLoopStop {
+    /// The model responded without calling any tools
+    Completed,
+    MaxIterations,
+    Timeout,
+}
+
+#[derive(Debug)]
+pub struct ToolLoopResult {
+    /// The request with all messages exchanged during the loop appended
+    pub history: ChatHistory,
+    pub trace: Vec<ToolLoopStep>,
+    pub stop: ToolLoopStop,
+}
+
+impl Context {
+    /// Repeatedly call the model and run the tools it asks for, until it responds without calling any tools
+    pub async fn run_tool_loop(&self, chat_completion_request: ChatHistoryBuilder, registry: &ToolRegistry, options: ToolLoopOptions) -> Result<ToolLoopResult> {
+        let request = chat_completion_request.tools(registry.tools());
+        let mut messages = request.clone().build()?.messages;
+        let mut trace = Vec::new();
+        let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+
+        let stop = loop {
+            if trace.len() >= options.max_iterations {
+                break ToolLoopStop::MaxIterations;
+            }
+
+            let completion = self.create_chat_completion_sync(request.clone().messages(messages.clone()));
+            let response = match deadline {
+                Some(deadline) => match tokio::time::timeout_at(deadline, completion).await {
+                    Ok(response) => response?,
+                    Err(_) => break ToolLoopStop::Timeout,
+                },
+                None => completion.await?,
+            };
+
+            let Some(message) = response.choices.first().map(|choice| choice.message.clone()) else {
+                trace.push(ToolLoopStep { response, executions: Vec::new() });
+                break ToolLoopStop::Completed;
+            };
+
+            let calls = message.tool_calls.clone().unwrap_or_default();
+            messages.push(message);
+            if calls.is_empty() {
+                trace.push(ToolLoopStep { response, executions: Vec::new() });
+                break ToolLoopStop::Completed;
+            }
+
+            // Every call gets a result, even if it times out, so that the history stays valid
+            let call_deadline = match (options.tool_timeout.map(|timeout| Instant::now() + timeout), deadline) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            let executions = join_all(calls.into_iter().map(|call| registry.execute(call, call_deadline))).await;
+            messages.extend(executions.iter().map(|execution| ChatMessage::tool_result(execution.call.id.clone(), execution.output.clone())));
+            trace.push(ToolLoopStep { response, executions });
+        };
+
+        Ok(ToolLoopResult {
+            history: request.messages(messages).build()?,
+            trace,
+            stop,
+        })
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use crate::{chat::{ChatHistory, ChatMessage, Tool}, context::Context, conversation::Conversation, error::Result};
+
+/// A conversation in the format used to fine-tune chat models, one per line of a JSONL file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatExample {
+    pub messages: Vec<ChatMessage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallel_tool_calls: Option<bool>,
+}
+
+impl From<ChatHistory> for ChatExample {
+    fn from(history: ChatHistory) -> Self {
+        Self {
+            messages: history.messages,
+            tools: history.tools,
+            parallel_tool_calls: history.parallel_tool_calls,
+        }
+    }
+}
+
+impl From<&Conversation> for ChatExample {
+    /// The messages of `conversation` with the tools declared in its settings
+    fn from(conversation: &Conversation) -> Self {
+        match conversation.to_history() {
+            Ok(history) => history.into(),
+            // Settings without a model still describe a valid transcript
+            Err(_) => Self {
+                messages: conversation.messages().to_vec(),
+                tools: None,
+                parallel_tool_calls: None,
+            },
+        }
+    }
+}
+
+impl ChatHistory {
+    pub async fn save_json(&self, path: impl AsRef<Path>) -> Result<()> {
+        Ok(tokio::fs::write(path, serde_json::to_vec_pretty(self)?).await?)
+    }
+
+    pub async fn load_json(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(serde_json::from_slice(&tokio::fs::read(path).await?)?)
+    }
+}
+
+impl Conversation {
+    /// Save the settings and history of this conversation as a [`ChatHistory`]
+    pub async fn save_json(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.to_history()?.save_json(path).await
+    }
+
+    /// Resume a conversation saved with [`save_json`](Conversation::save_json)
+    pub async fn load_json(context: Context, path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_request(context, ChatHistory::load_json(path).await?.into())
+    }
+}
+
+pub fn to_jsonl(examples: &[ChatExample]) -> Result<String> {
+    let mut jsonl = String::new();
+    for example in examples {
+        jsonl.push_str(&serde_json::to_string(example)?);
+        jsonl.push('\n');
+    }
+    Ok(jsonl)
+}
+
+/// Parse JSONL, skipping blank lines
+pub fn from_jsonl(jsonl: &str) -> Result<Vec<ChatExample>> {
+    jsonl.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+pub async fn save_jsonl(path: impl AsRef<Path>, examples: &[ChatExample]) -> Result<()> {
+    Ok(tokio::fs::write(path, to_jsonl(examples)?).await?)
+}
+
+pub async fn load_jsonl(path: impl AsRef<Path>) -> Result<Vec<ChatExample>> {
+    from_jsonl(&tokio::fs::read_to_string(path).await?)
+}
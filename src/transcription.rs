@@ -1,9 +1,10 @@
+use std::fmt::Display;
+
 use derive_builder::Builder;
-use reqwest::{multipart::Form, Client};
 use serde::Deserialize;
 use tokio::fs::File;
 
-use crate::{context::{API_URL, Context}, util::FileResource};
+use crate::{metadata::WithMetadata, context::Context, error::Result, request::ApiRequest, util::{ExtraFields, FileResource, MultipartForm}};
 
 #[derive(Debug, Clone)]
 pub enum AudioResponseFormat {
@@ -12,6 +13,8 @@ pub enum AudioResponseFormat {
     Srt,
     Vtt,
     VerboseJson,
+    /// A format this version of the crate does not know about
+    Unknown(String),
 }
 
 #[derive(Debug)]
@@ -48,15 +51,16 @@ impl AudioFile {
     }
 }
 
-impl ToString for AudioResponseFormat {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for AudioResponseFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             AudioResponseFormat::Text => "text",
             AudioResponseFormat::Json => "json",
             AudioResponseFormat::Srt => "srt",
             AudioResponseFormat::Vtt => "vtt",
             AudioResponseFormat::VerboseJson => "verbose_json",
-        }.to_string()
+            AudioResponseFormat::Unknown(format) => format,
+        })
     }
 }
 
@@ -80,13 +84,26 @@ pub struct TranscriptionRequest {
 #[derive(Debug, Deserialize)]
 pub struct TranscriptionResponse {
     pub text: String,
+    /// Only sent with [`AudioResponseFormat::VerboseJson`]
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Length of the audio in seconds. Only sent with [`AudioResponseFormat::VerboseJson`]
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 impl Context {
-    pub async fn create_transcription(&self, req: TranscriptionRequest) -> anyhow::Result<TranscriptionResponse> {
-        let mut form = Form::new();
+    pub async fn create_transcription(&self, req: TranscriptionRequest) -> Result<TranscriptionResponse> {
+        Ok(self.create_transcription_with_metadata(req).await?.data)
+    }
+
+    /// Like [`create_transcription`](Context::create_transcription), along with the metadata of the response
+    pub async fn create_transcription_with_metadata(&self, req: TranscriptionRequest) -> Result<WithMetadata<TranscriptionResponse>> {
+        let mut form = MultipartForm::new();
         let file_name = req.file.file_name();
-        form = FileResource::from(req.file.file()).write_file_named(form, "file", file_name);
+        form = FileResource::from(req.file.file()).write_file_named(form, "file", file_name).await?;
         form = form.text("model", req.model);
 
         if let Some(response_format) = req.response_format {
@@ -104,14 +121,6 @@ impl Context {
             form = form.text("language", language.to_string());
         }
         
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/audio/transcriptions")))
-                .multipart(form)
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<TranscriptionResponse>()
-                .await?
-        )
+        self.execute_json_with_metadata::<TranscriptionResponse>(ApiRequest::post("audio/transcriptions").multipart(form)).await
     }
 }
\ No newline at end of file
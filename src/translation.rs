@@ -1,8 +1,6 @@
 use derive_builder::Builder;
-use reqwest::{multipart::{Form, Part}, Body, Client};
-use tokio_util::codec::{FramedRead, BytesCodec};
 
-use crate::{context::{API_URL, Context}, transcription::TranscriptionResponse};
+use crate::{metadata::WithMetadata, context::Context, error::Result, request::ApiRequest, transcription::TranscriptionResponse, util::{FileResource, MultipartForm}};
 use crate::transcription::{AudioFile, AudioResponseFormat};
 
 type TranslationResponse = TranscriptionResponse;
@@ -23,10 +21,15 @@ pub struct TranslationRequest {
 }
 
 impl Context {
-    pub async fn create_translation(&self, req: TranslationRequest) -> anyhow::Result<TranslationResponse> {
-        let mut form = Form::new();
+    pub async fn create_translation(&self, req: TranslationRequest) -> Result<TranslationResponse> {
+        Ok(self.create_translation_with_metadata(req).await?.data)
+    }
+
+    /// Like [`create_translation`](Context::create_translation), along with the metadata of the response
+    pub async fn create_translation_with_metadata(&self, req: TranslationRequest) -> Result<WithMetadata<TranslationResponse>> {
+        let mut form = MultipartForm::new();
         let file_name = req.file.file_name();
-        form = form.part("file", Part::stream(Body::wrap_stream(FramedRead::new(req.file.file(), BytesCodec::new()))).file_name(file_name));
+        form = FileResource::from(req.file.file()).write_file_named(form, "file", file_name).await?;
         form = form.text("model", req.model);
 
         if let Some(response_format) = req.response_format {
@@ -40,14 +43,6 @@ impl Context {
             form = form.text("temperature", temperature.to_string());
         }
         
-        Ok(
-            self.with_auth(Client::builder().build()?.post(&format!("{API_URL}/v1/audio/translations")))
-                .multipart(form)
-                .send()
-                .await?
-                .error_for_status()?
-                .json::<TranslationResponse>()
-                .await?
-        )
+        self.execute_json_with_metadata::<TranslationResponse>(ApiRequest::post("audio/translations").multipart(form)).await
     }
 }
\ No newline at end of file
@@ -1,12 +1,24 @@
+use std::time::Duration;
+
 use base64::{prelude::BASE64_STANDARD, Engine};
-use reqwest::{multipart::{Form, Part}, Body};
+use bytes::Bytes;
+use reqwest::multipart::{Form, Part};
+use schemars::{JsonSchema, gen::SchemaSettings};
 use serde::Deserialize;
-use tokio_util::codec::{FramedRead, BytesCodec};
+use tokio::io::AsyncReadExt;
+
+use crate::error::Result;
+
+/// Fields of a response that are not known to this crate, as sent by the server
+pub type ExtraFields = serde_json::Map<String, serde_json::Value>;
 
 #[derive(Debug, Deserialize)]
 pub struct DataList<T> {
     pub data: Vec<T>,
-    /* pub object: "list", */
+    #[serde(default)]
+    pub object: String,
+    #[serde(flatten)]
+    pub extra: ExtraFields,
 }
 
 
@@ -17,20 +29,39 @@ pub enum FileResource {
 }
 
 impl FileResource {
-    pub(crate) fn write_file_named(self, form: Form, part_name: impl Into<String>, file_name: impl Into<String>) -> Form {
-        match self {
-            FileResource::File(file) =>
-                form.part(part_name.into(), Part::stream(Body::wrap_stream(FramedRead::new(file, BytesCodec::new()))).file_name(file_name.into())),
-            FileResource::Data(data) =>
-                form.part(part_name.into(), Part::bytes(BASE64_STANDARD.encode(data.as_slice()).as_bytes().to_owned()).file_name(file_name.into())),
-        }
+    /// Read the whole resource into memory so that it can be sent more than once
+    pub(crate) async fn into_bytes(self) -> Result<Bytes> {
+        Ok(match self {
+            FileResource::File(mut file) => {
+                let mut data = Vec::new();
+                file.read_to_end(&mut data).await?;
+                Bytes::from(data)
+            },
+            FileResource::Data(data) => Bytes::from(BASE64_STANDARD.encode(data.as_slice())),
+        })
+    }
+
+    /// Read the whole resource into memory as is
+    pub(crate) async fn read_to_vec(self) -> Result<Vec<u8>> {
+        Ok(match self {
+            FileResource::File(mut file) => {
+                let mut data = Vec::new();
+                file.read_to_end(&mut data).await?;
+                data
+            },
+            FileResource::Data(data) => data,
+        })
     }
 
-    pub(crate) fn write_file(self, form: Form, name: impl Into<String>) -> Form {
+    pub(crate) async fn write_file_named(self, form: MultipartForm, part_name: impl Into<String>, file_name: impl Into<String>) -> Result<MultipartForm> {
+        Ok(form.file(part_name, file_name, self.into_bytes().await?))
+    }
+
+    pub(crate) async fn write_file(self, form: MultipartForm, name: impl Into<String>) -> Result<MultipartForm> {
         let name = name.into();
-        self.write_file_named(form, name.clone(), name)
+        self.write_file_named(form, name.clone(), name).await
     }
-    
+
 }
 
 impl From<tokio::fs::File> for FileResource {
@@ -43,4 +74,132 @@ impl From<Vec<u8>> for FileResource {
     fn from(data: Vec<u8>) -> Self {
         Self::Data(data)
     }
-}
\ No newline at end of file
+}
+
+/// `data:` URL embedding `data` with the given MIME type
+pub(crate) fn data_url(mime_type: &str, data: &[u8]) -> String {
+    format!("data:{mime_type};base64,{}", BASE64_STANDARD.encode(data))
+}
+
+#[derive(Debug, Clone)]
+enum FormField {
+    Text(String),
+    File {
+        file_name: String,
+        data: Bytes,
+    },
+}
+
+/// Multipart form held in memory, so that a fresh [`Form`] can be built for every attempt at a request
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MultipartForm {
+    fields: Vec<(String, FormField)>,
+}
+
+impl MultipartForm {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn text(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.fields.push((name.into(), FormField::Text(value.into())));
+        self
+    }
+
+    pub(crate) fn file(mut self, name: impl Into<String>, file_name: impl Into<String>, data: Bytes) -> Self {
+        self.fields.push((name.into(), FormField::File { file_name: file_name.into(), data }));
+        self
+    }
+
+    pub(crate) fn text_value(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find_map(|(field_name, field)| match field {
+            FormField::Text(value) if field_name == name => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    pub(crate) fn to_form(&self) -> Form {
+        self.fields.iter().fold(Form::new(), |form, (name, field)| match field {
+            FormField::Text(value) => form.text(name.clone(), value.clone()),
+            FormField::File { file_name, data } =>
+                form.part(name.clone(), Part::stream(data.clone()).file_name(file_name.clone())),
+        })
+    }
+}
+
+/// Parse durations in the format used by the `x-ratelimit-reset-*` headers, e.g. `1s`, `6m0s` or `20ms`
+pub(crate) fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = 0f64;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+        let number = rest[..number_end].parse::<f64>().ok()?;
+        rest = &rest[number_end..];
+
+        let unit_end = rest.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(rest.len());
+        total += number * match &rest[..unit_end] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        rest = &rest[unit_end..];
+    }
+
+    Duration::try_from_secs_f64(total).ok()
+}
+
+/// JSON Schema of a type, with all definitions inlined as the API does not resolve references
+pub(crate) fn json_schema<T: JsonSchema>() -> serde_json::Value {
+    let schema = SchemaSettings::draft07()
+        .with(|settings| settings.inline_subschemas = true)
+        .into_generator()
+        .into_root_schema_for::<T>();
+
+    let mut schema = serde_json::to_value(schema).expect("Schemas are always valid JSON");
+    if let Some(object) = schema.as_object_mut() {
+        object.remove("$schema");
+        object.remove("title");
+    }
+    schema
+}
+
+/// JSON Schema of a type, restricted to what the API accepts for strict structured outputs: objects may not have
+/// properties beyond the declared ones, and all properties are required. `Option` fields are still nullable
+pub(crate) fn strict_json_schema<T: JsonSchema>() -> serde_json::Value {
+    fn restrict(schema: &mut serde_json::Value) {
+        match schema {
+            serde_json::Value::Object(object) => {
+                if let Some(properties) = object.get("properties").and_then(|properties| properties.as_object()) {
+                    let required = properties.keys().cloned().map(serde_json::Value::String).collect();
+                    object.insert("required".to_string(), serde_json::Value::Array(required));
+                    object.insert("additionalProperties".to_string(), serde_json::Value::Bool(false));
+                }
+
+                // Numeric formats such as `uint32` are not supported
+                if object.get("format").and_then(|format| format.as_str()).is_some_and(|format| ["int", "uint", "float", "double"].iter().any(|prefix| format.starts_with(prefix))) {
+                    object.remove("format");
+                }
+
+                for (key, value) in object.iter_mut() {
+                    match value.as_object_mut() {
+                        // Maps from property names to schemas, rather than schemas themselves
+                        Some(properties) if key == "properties" => properties.values_mut().for_each(restrict),
+                        _ => restrict(value),
+                    }
+                }
+            },
+            serde_json::Value::Array(values) => values.iter_mut().for_each(restrict),
+            _ => {},
+        }
+    }
+
+    let mut schema = json_schema::<T>();
+    restrict(&mut schema);
+    schema
+}